@@ -0,0 +1,107 @@
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use libactionkv::storage::MemStorage;
+use libactionkv::ActionKV;
+
+// value sizes in bytes, and how many keys each store holds
+const SIZES: [usize; 3] = [16, 256, 4096];
+const KEYS: usize = 1000;
+
+fn key(i: usize) -> Vec<u8> {
+    format!("key-{i:06}").into_bytes()
+}
+
+/// A loaded store with `KEYS` keys, each written `versions` times
+fn store(size: usize, versions: usize) -> ActionKV<MemStorage> {
+    let mut store = ActionKV::with_storage(MemStorage::new());
+    let value = vec![b'v'; size];
+    for _ in 0..versions {
+        for i in 0..KEYS {
+            store.insert(&key(i), &value).unwrap();
+        }
+    }
+    store
+}
+
+fn insert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("insert");
+    for size in SIZES {
+        let value = vec![b'v'; size];
+        group.throughput(Throughput::Bytes((size * KEYS) as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &value, |b, value| {
+            b.iter_batched_ref(
+                || ActionKV::with_storage(MemStorage::new()),
+                |store| {
+                    for i in 0..KEYS {
+                        store.insert(&key(i), value).unwrap();
+                    }
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get");
+    for size in SIZES {
+        let store = store(size, 1);
+        group.throughput(Throughput::Bytes((size * KEYS) as u64));
+        group.bench_function(BenchmarkId::from_parameter(size), |b| {
+            b.iter(|| {
+                for i in 0..KEYS {
+                    black_box(store.get(&key(i)).unwrap());
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn load(c: &mut Criterion) {
+    let mut group = c.benchmark_group("load");
+    for size in SIZES {
+        let bytes = store(size, 1).into_storage().into_bytes();
+        group.throughput(Throughput::Bytes(bytes.len() as u64));
+        group.bench_function(BenchmarkId::from_parameter(size), |b| {
+            b.iter_batched(
+                || ActionKV::with_storage(MemStorage::from_bytes(bytes.clone())),
+                |mut store| {
+                    store.load().unwrap();
+                    store
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn compact(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compact");
+    for size in SIZES {
+        // three of every four records are garbage
+        let bytes = store(size, 4).into_storage().into_bytes();
+        group.throughput(Throughput::Bytes(bytes.len() as u64));
+        group.bench_function(BenchmarkId::from_parameter(size), |b| {
+            b.iter_batched(
+                || {
+                    let mut store = ActionKV::with_storage(MemStorage::from_bytes(bytes.clone()));
+                    store.load().unwrap();
+                    store
+                },
+                |mut store| {
+                    store.compact().unwrap();
+                    store
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, insert, get, load, compact);
+criterion_main!(benches);
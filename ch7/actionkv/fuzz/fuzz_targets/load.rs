@@ -0,0 +1,23 @@
+#![no_main]
+
+// Loading any bytes as a log either builds an index or returns an error.
+// The first byte picks the index mode so hashed lookups are covered too.
+
+use libactionkv::index::IndexMode;
+use libactionkv::storage::MemStorage;
+use libactionkv::ActionKV;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((mode, log)) = data.split_first() else {
+        return;
+    };
+    let mut store = ActionKV::with_storage(MemStorage::from_bytes(log.to_vec()));
+    if mode & 1 == 1 {
+        store.set_index_mode(IndexMode::Hashed);
+    }
+    if store.load().is_ok() {
+        let _ = store.scan(b"");
+        let _ = store.stats(4);
+    }
+});
@@ -0,0 +1,14 @@
+#![no_main]
+
+// Lengths in a hostile header can claim gigabytes, the parser must only
+// allocate what it actually reads. libFuzzer's -malloc_limit_mb (the rss
+// limit by default) turns an allocation sized from the header into a crash.
+
+use libactionkv::storage::MemStorage;
+use libactionkv::ActionKV;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut f = data;
+    let _ = ActionKV::<MemStorage>::process_record(&mut f, 0);
+});
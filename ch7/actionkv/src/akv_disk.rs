@@ -1,5 +1,7 @@
+use std::process::ExitCode;
+
 use libactionkv::utils::run;
 
-fn main() {
-    run(true);
+fn main() -> ExitCode {
+    run(true)
 }
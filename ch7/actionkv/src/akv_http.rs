@@ -0,0 +1,75 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::Parser;
+use libactionkv::async_kv::AsyncActionKV;
+use libactionkv::index::IndexMode;
+use libactionkv::merge::BuiltinMerge;
+use libactionkv::utils::init_tracing;
+use libactionkv::{http, ActionKV, ActionKvError, Result};
+use tokio::net::TcpListener;
+use tracing::{error, info};
+
+/// Serves an ActionKV store over HTTP
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// FILE for ActionKV
+    #[arg(value_name = "FILE")]
+    fname: PathBuf,
+
+    /// Address to listen on
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    addr: SocketAddr,
+
+    /// How keys are kept in memory, hashed keeps only a hash and an offset per key
+    #[arg(long, value_enum, default_value_t = IndexMode::Full)]
+    index: IndexMode,
+
+    /// Operator that folds merge operands already in the file
+    #[arg(long, value_enum, value_name = "OPERATOR")]
+    merge_operator: Option<BuiltinMerge>,
+}
+
+fn open_store(args: &Args) -> Result<ActionKV> {
+    let mut store = ActionKV::open(&args.fname)?;
+    store.set_index_mode(args.index);
+    if let Some(operator) = args.merge_operator {
+        store.set_merge_operator(operator.operator());
+    }
+    store.load()?;
+    Ok(store)
+}
+
+async fn serve(addr: SocketAddr, store: AsyncActionKV) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("listening on {}", listener.local_addr()?);
+    axum::serve(listener, http::router(store))
+        .with_graceful_shutdown(async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    init_tracing();
+
+    let store = match open_store(&args) {
+        Ok(store) => store,
+        Err(err) => {
+            error!("unable to open {:?}: {err}", args.fname);
+            return ExitCode::from(err.exit_code());
+        }
+    };
+
+    let runtime = tokio::runtime::Runtime::new().expect("unable to start the runtime");
+    match runtime.block_on(serve(args.addr, AsyncActionKV::new(store))) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            error!("unable to serve on {}: {err}", args.addr);
+            ExitCode::from(ActionKvError::from(err).exit_code())
+        }
+    }
+}
@@ -1,5 +1,7 @@
+use std::process::ExitCode;
+
 use libactionkv::utils::run;
 
-fn main() {
-    run(false);
+fn main() -> ExitCode {
+    run(false)
 }
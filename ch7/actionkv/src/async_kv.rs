@@ -0,0 +1,146 @@
+use std::io;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+
+use tokio::sync::oneshot;
+
+use crate::storage::{FileStorage, Storage};
+use crate::{ActionKV, ActionKvError, ByteString, KeyValuePair, Result};
+
+type Job<S> = Box<dyn FnOnce(&mut ActionKV<S>) + Send>;
+
+/// Async handle to a store owned by a dedicated I/O thread.
+///
+/// Every call is sent to that thread over a channel, so blocking file I/O
+/// never runs on the async runtime. The on-disk format is the one
+/// `ActionKV` uses. Clones share the same store.
+pub struct AsyncActionKV<S: Storage = FileStorage> {
+    jobs: mpsc::Sender<Job<S>>,
+}
+
+impl<S: Storage> Clone for AsyncActionKV<S> {
+    fn clone(&self) -> Self {
+        Self {
+            jobs: self.jobs.clone(),
+        }
+    }
+}
+
+fn stopped() -> ActionKvError {
+    io::Error::other("store thread has stopped").into()
+}
+
+impl AsyncActionKV {
+    /// Open and load the file at `path` on the I/O thread
+    pub async fn open(path: PathBuf) -> Result<Self> {
+        let (opened, result) = oneshot::channel();
+        let (jobs, queue) = mpsc::channel::<Job<FileStorage>>();
+
+        thread::spawn(move || {
+            let store = ActionKV::open(&path).and_then(|mut store| {
+                store.load()?;
+                Ok(store)
+            });
+            match store {
+                Ok(store) => {
+                    let _ = opened.send(Ok(()));
+                    serve(store, queue);
+                }
+                Err(err) => {
+                    let _ = opened.send(Err(err));
+                }
+            }
+        });
+
+        result.await.map_err(|_| stopped())??;
+        Ok(Self { jobs })
+    }
+}
+
+impl<S: Storage + Send + 'static> AsyncActionKV<S> {
+    /// Move an opened and loaded store onto its own I/O thread
+    pub fn new(store: ActionKV<S>) -> Self {
+        let (jobs, queue) = mpsc::channel::<Job<S>>();
+        thread::spawn(move || serve(store, queue));
+        Self { jobs }
+    }
+
+    /// Run `job` on the I/O thread and wait for its result
+    pub async fn call<T, F>(&self, job: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut ActionKV<S>) -> Result<T> + Send + 'static,
+    {
+        let (done, result) = oneshot::channel();
+        self.jobs
+            .send(Box::new(move |store| {
+                let _ = done.send(job(store));
+            }))
+            .map_err(|_| stopped())?;
+        result.await.map_err(|_| stopped())?
+    }
+
+    pub async fn get(&self, key: impl Into<ByteString>) -> Result<Option<ByteString>> {
+        let key = key.into();
+        self.call(move |store| store.get(&key)).await
+    }
+
+    pub async fn insert(
+        &self,
+        key: impl Into<ByteString>,
+        value: impl Into<ByteString>,
+    ) -> Result<()> {
+        let (key, value) = (key.into(), value.into());
+        self.call(move |store| store.insert(&key, &value)).await
+    }
+
+    pub async fn merge(
+        &self,
+        key: impl Into<ByteString>,
+        operand: impl Into<ByteString>,
+    ) -> Result<()> {
+        let (key, operand) = (key.into(), operand.into());
+        self.call(move |store| store.merge(&key, &operand)).await
+    }
+
+    pub async fn delete(&self, key: impl Into<ByteString>) -> Result<()> {
+        let key = key.into();
+        self.call(move |store| store.delete(&key)).await
+    }
+
+    pub async fn scan(&self, prefix: impl Into<ByteString>) -> Result<Vec<KeyValuePair>> {
+        let prefix = prefix.into();
+        self.call(move |store| store.scan(&prefix)).await
+    }
+}
+
+// runs until every handle has been dropped
+fn serve<S: Storage>(mut store: ActionKV<S>, queue: mpsc::Receiver<Job<S>>) {
+    for job in queue {
+        job(&mut store);
+    }
+}
+
+#[cfg(test)]
+mod async_test {
+    use super::*;
+    use crate::storage::MemStorage;
+
+    #[tokio::test]
+    async fn roundtrip() {
+        let store = AsyncActionKV::new(ActionKV::with_storage(MemStorage::new()));
+        store.insert("a", "1").await.unwrap();
+        store.clone().insert("ab", "2").await.unwrap();
+
+        assert_eq!(store.get("a").await.unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.scan("a").await.unwrap().len(), 2);
+
+        store.delete("a").await.unwrap();
+        assert_eq!(store.get("a").await.unwrap(), None);
+        assert!(matches!(
+            store.delete("a").await,
+            Err(ActionKvError::NotFound(_))
+        ));
+    }
+}
@@ -0,0 +1,167 @@
+use std::f64::consts::LN_2;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::checksum::{fnv1a_hash, FNV_OFFSET_BASIS};
+
+// any odd constant works as a second seed for double hashing
+const SECOND_SEED: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// Bloom filter over the keys of a store.
+///
+/// A negative answer from `contains` is definite, so `get` can return
+/// `None` for a missing key without touching the file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    hashes: u32,
+    capacity: usize,
+    fp_rate: f64,
+}
+
+/// Counters of how the filter answered lookups, kept per process.
+#[derive(Debug, Default)]
+pub struct BloomStats {
+    lookups: AtomicU64,
+    negatives: AtomicU64,
+    false_positives: AtomicU64,
+}
+
+impl BloomFilter {
+    /// Filter sized for `capacity` keys at the given false positive rate
+    // see: https://en.wikipedia.org/wiki/Bloom_filter#Optimal_number_of_hash_functions
+    pub fn new(capacity: usize, fp_rate: f64) -> Self {
+        let capacity = capacity.max(1);
+        let fp_rate = fp_rate.clamp(f64::MIN_POSITIVE, 0.5);
+
+        let num_bits = (-(capacity as f64) * fp_rate.ln() / (LN_2 * LN_2)).ceil() as usize;
+        let words = num_bits.div_ceil(64).max(1);
+        let hashes = ((words * 64) as f64 / capacity as f64 * LN_2)
+            .round()
+            .max(1.0) as u32;
+
+        Self {
+            bits: vec![0; words],
+            hashes,
+            capacity,
+            fp_rate,
+        }
+    }
+
+    fn num_bits(&self) -> u64 {
+        self.bits.len() as u64 * 64
+    }
+
+    fn bit_indexes(&self, key: &[u8]) -> impl Iterator<Item = u64> {
+        let h1 = fnv1a_hash(FNV_OFFSET_BASIS, key);
+        let h2 = fnv1a_hash(SECOND_SEED, key) | 1;
+        let num_bits = self.num_bits();
+        (0..self.hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+    }
+
+    pub fn insert(&mut self, key: &[u8]) {
+        for i in self.bit_indexes(key) {
+            self.bits[(i / 64) as usize] |= 1 << (i % 64);
+        }
+    }
+
+    /// `false` means the key was never inserted, `true` means it may have been
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.bit_indexes(key)
+            .all(|i| self.bits[(i / 64) as usize] & (1 << (i % 64)) != 0)
+    }
+
+    /// Forget every key but keep the size of the filter
+    pub fn clear(&mut self) {
+        self.bits.iter_mut().for_each(|word| *word = 0);
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn fp_rate(&self) -> f64 {
+        self.fp_rate
+    }
+
+    /// Memory used by the bit array in bytes
+    pub fn size_in_bytes(&self) -> usize {
+        self.bits.len() * 8
+    }
+}
+
+impl BloomStats {
+    pub(crate) fn record_negative(&self) {
+        self.lookups.fetch_add(1, Ordering::Relaxed);
+        self.negatives.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_positive(&self, present: bool) {
+        self.lookups.fetch_add(1, Ordering::Relaxed);
+        if !present {
+            self.false_positives.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn lookups(&self) -> u64 {
+        self.lookups.load(Ordering::Relaxed)
+    }
+
+    /// Lookups answered by the filter alone
+    pub fn negatives(&self) -> u64 {
+        self.negatives.load(Ordering::Relaxed)
+    }
+
+    /// Lookups the filter passed on but the key was not present
+    pub fn false_positives(&self) -> u64 {
+        self.false_positives.load(Ordering::Relaxed)
+    }
+
+    /// Share of lookups for missing keys that the filter failed to reject
+    pub fn false_positive_rate(&self) -> f64 {
+        let false_positives = self.false_positives() as f64;
+        let missing = false_positives + self.negatives() as f64;
+        if missing == 0.0 {
+            0.0
+        } else {
+            false_positives / missing
+        }
+    }
+}
+
+#[cfg(test)]
+mod bloom_test {
+    use super::*;
+
+    #[test]
+    fn no_false_negatives() {
+        let mut bloom = BloomFilter::new(1000, 0.01);
+        for i in 0..1000 {
+            bloom.insert(format!("key{i}").as_bytes());
+        }
+        for i in 0..1000 {
+            assert!(bloom.contains(format!("key{i}").as_bytes()));
+        }
+    }
+
+    #[test]
+    fn false_positive_rate_near_target() {
+        let mut bloom = BloomFilter::new(1000, 0.01);
+        for i in 0..1000 {
+            bloom.insert(format!("key{i}").as_bytes());
+        }
+        let false_positives = (0..10_000)
+            .filter(|i| bloom.contains(format!("missing{i}").as_bytes()))
+            .count();
+        assert!(false_positives < 300, "{false_positives} false positives");
+    }
+
+    #[test]
+    fn clear_forgets_keys() {
+        let mut bloom = BloomFilter::new(10, 0.01);
+        bloom.insert(b"abc");
+        bloom.clear();
+        assert!(!bloom.contains(b"abc"));
+    }
+}
@@ -2,6 +2,9 @@ use crc::{Crc, CRC_32_ISO_HDLC};
 
 const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
 
+pub const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
 pub fn parity_bit(bytes: &[u8]) -> u8 {
     (bytes.iter().fold(0, |ones, b| {
         if b.count_ones() % 2 == 0 {
@@ -17,6 +20,23 @@ pub fn crc32_checksum(bytes: &[u8]) -> u32 {
     CRC.checksum(bytes)
 }
 
+/// Checksum of the concatenation of `parts` without copying them together
+pub fn crc32_checksum_parts(parts: &[&[u8]]) -> u32 {
+    let mut digest = CRC.digest();
+    for part in parts {
+        digest.update(part);
+    }
+    digest.finalize()
+}
+
+// see: https://en.wikipedia.org/wiki/Fowler%E2%80%93Noll%E2%80%93Vo_hash_function
+// hashes are persisted, so this must not change between builds
+pub fn fnv1a_hash(seed: u64, bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(seed, |hash, &b| (hash ^ b as u64).wrapping_mul(FNV_PRIME))
+}
+
 #[cfg(test)]
 mod checksum_test {
     use super::*;
@@ -32,5 +52,14 @@ mod checksum_test {
         // compare with crc32 from python zlib
         assert_eq!(crc32_checksum(b"abc"), 891568578);
         assert_eq!(crc32_checksum(b"abcd"), 3984772369);
+        assert_eq!(crc32_checksum_parts(&[b"ab", b"", b"cd"]), 3984772369);
+    }
+
+    #[test]
+    fn fnv1a_hash_test() {
+        // test vectors from the FNV reference implementation
+        assert_eq!(fnv1a_hash(FNV_OFFSET_BASIS, b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a_hash(FNV_OFFSET_BASIS, b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(fnv1a_hash(FNV_OFFSET_BASIS, b"foobar"), 0x85944171f73967e8);
     }
 }
@@ -0,0 +1,91 @@
+use std::error::Error;
+use std::fmt::Display;
+use std::io;
+
+use crate::ByteString;
+
+pub type Result<T> = std::result::Result<T, ActionKvError>;
+
+#[derive(Debug)]
+pub enum ActionKvError {
+    /// The key is not in the index
+    NotFound(ByteString),
+    /// A record failed its checksum or could not be decoded
+    Corruption {
+        offset: u64,
+    },
+    KeyTooLarge {
+        len: u64,
+        max: u64,
+    },
+    ValueTooLarge {
+        len: u64,
+        max: u64,
+    },
+    /// Another process holds the lock on the file
+    Locked,
+    /// A merge record was written or read without a merge operator
+    NoMergeOperator,
+    Io(io::Error),
+}
+
+impl ActionKvError {
+    /// Process exit code the CLI reports the error with
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            ActionKvError::NotFound(_) => 2,
+            ActionKvError::Corruption { .. } => 3,
+            ActionKvError::KeyTooLarge { .. } | ActionKvError::ValueTooLarge { .. } => 4,
+            ActionKvError::Locked => 5,
+            ActionKvError::Io(_) => 6,
+            ActionKvError::NoMergeOperator => 7,
+        }
+    }
+
+    /// A record cut short by the end of the file, which is how a torn
+    /// write at the tail of the log looks
+    pub fn is_eof(&self) -> bool {
+        matches!(self, ActionKvError::Io(err) if err.kind() == io::ErrorKind::UnexpectedEof)
+    }
+}
+
+impl Display for ActionKvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ActionKvError::NotFound(key) => {
+                write!(
+                    f,
+                    "{:?} does not exist in index",
+                    String::from_utf8_lossy(key)
+                )
+            }
+            ActionKvError::Corruption { offset } => {
+                write!(f, "data corruption encountered at offset {offset}")
+            }
+            ActionKvError::KeyTooLarge { len, max } => {
+                write!(f, "key of {len} bytes is larger than {max} bytes")
+            }
+            ActionKvError::ValueTooLarge { len, max } => {
+                write!(f, "value of {len} bytes is larger than {max} bytes")
+            }
+            ActionKvError::Locked => write!(f, "file is locked by another process"),
+            ActionKvError::NoMergeOperator => write!(f, "no merge operator is registered"),
+            ActionKvError::Io(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl Error for ActionKvError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ActionKvError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ActionKvError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
@@ -0,0 +1,299 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use axum::body::Bytes;
+use axum::extract::{Path, Query, State};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::async_kv::AsyncActionKV;
+use crate::storage::Storage;
+use crate::ActionKvError;
+
+// operations counted for /metrics, in the order of `Metrics` arrays
+const OPS: [&str; 4] = ["get", "put", "delete", "scan"];
+const GET: usize = 0;
+const PUT: usize = 1;
+const DELETE: usize = 2;
+const SCAN: usize = 3;
+
+#[derive(Default)]
+struct Metrics {
+    requests: [AtomicU64; OPS.len()],
+    errors: [AtomicU64; OPS.len()],
+}
+
+impl Metrics {
+    fn record<T>(&self, op: usize, result: &Result<T, ApiError>) {
+        self.requests[op].fetch_add(1, Ordering::Relaxed);
+        if result.is_err() {
+            self.errors[op].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+struct Gateway<S: Storage> {
+    store: AsyncActionKV<S>,
+    metrics: Metrics,
+}
+
+type Shared<S> = State<Arc<Gateway<S>>>;
+
+/// Routes of the REST gateway over `store`:
+///
+/// - `GET`, `PUT` and `DELETE /kv/{key}` read, write and remove the value
+///   of a key, which travels as the raw request or response body
+/// - `GET /kv?prefix=` lists the pairs whose keys start with the prefix as JSON
+/// - `GET /health` answers once the store thread answers
+/// - `GET /metrics` reports request counters and store sizes for Prometheus
+pub fn router<S: Storage + Send + 'static>(store: AsyncActionKV<S>) -> Router {
+    let gateway = Arc::new(Gateway {
+        store,
+        metrics: Metrics::default(),
+    });
+    Router::new()
+        .route(
+            "/kv/{*key}",
+            get(get_key::<S>).put(put_key::<S>).delete(delete_key::<S>),
+        )
+        .route("/kv", get(scan::<S>))
+        .route("/health", get(health::<S>))
+        .route("/metrics", get(metrics::<S>))
+        .with_state(gateway)
+}
+
+/// A store error sent back as `{"error": ...}` with a matching status
+struct ApiError(ActionKvError);
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+impl From<ActionKvError> for ApiError {
+    fn from(value: ActionKvError) -> Self {
+        Self(value)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = match &self.0 {
+            ActionKvError::NotFound(_) => StatusCode::NOT_FOUND,
+            ActionKvError::KeyTooLarge { .. } | ActionKvError::ValueTooLarge { .. } => {
+                StatusCode::PAYLOAD_TOO_LARGE
+            }
+            ActionKvError::NoMergeOperator => StatusCode::BAD_REQUEST,
+            ActionKvError::Locked => StatusCode::SERVICE_UNAVAILABLE,
+            ActionKvError::Corruption { .. } | ActionKvError::Io(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+        let body = ErrorBody {
+            error: self.0.to_string(),
+        };
+        (status, Json(body)).into_response()
+    }
+}
+
+async fn get_key<S: Storage + Send + 'static>(
+    State(gateway): Shared<S>,
+    Path(key): Path<String>,
+) -> Result<Vec<u8>, ApiError> {
+    let result = match gateway.store.get(key.as_bytes()).await {
+        Ok(Some(value)) => Ok(value),
+        Ok(None) => Err(ActionKvError::NotFound(key.into_bytes()).into()),
+        Err(err) => Err(err.into()),
+    };
+    gateway.metrics.record(GET, &result);
+    result
+}
+
+async fn put_key<S: Storage + Send + 'static>(
+    State(gateway): Shared<S>,
+    Path(key): Path<String>,
+    value: Bytes,
+) -> Result<StatusCode, ApiError> {
+    let result = gateway
+        .store
+        .insert(key, value.to_vec())
+        .await
+        .map(|_| StatusCode::NO_CONTENT)
+        .map_err(ApiError::from);
+    gateway.metrics.record(PUT, &result);
+    result
+}
+
+async fn delete_key<S: Storage + Send + 'static>(
+    State(gateway): Shared<S>,
+    Path(key): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let result = gateway
+        .store
+        .delete(key)
+        .await
+        .map(|_| StatusCode::NO_CONTENT)
+        .map_err(ApiError::from);
+    gateway.metrics.record(DELETE, &result);
+    result
+}
+
+#[derive(Deserialize)]
+struct ScanParams {
+    #[serde(default)]
+    prefix: String,
+}
+
+/// Keys and values are sent as UTF-8, invalid bytes are replaced
+#[derive(Serialize)]
+struct Pair {
+    key: String,
+    value: String,
+}
+
+async fn scan<S: Storage + Send + 'static>(
+    State(gateway): Shared<S>,
+    Query(params): Query<ScanParams>,
+) -> Result<Json<Vec<Pair>>, ApiError> {
+    let result = gateway
+        .store
+        .scan(params.prefix)
+        .await
+        .map(|pairs| {
+            let pairs = pairs
+                .into_iter()
+                .map(|kv| Pair {
+                    key: String::from_utf8_lossy(&kv.key).into_owned(),
+                    value: String::from_utf8_lossy(&kv.value).into_owned(),
+                })
+                .collect();
+            Json(pairs)
+        })
+        .map_err(ApiError::from);
+    gateway.metrics.record(SCAN, &result);
+    result
+}
+
+async fn health<S: Storage + Send + 'static>(State(gateway): Shared<S>) -> Response {
+    match gateway.store.call(|_| Ok(())).await {
+        Ok(()) => "ok\n".into_response(),
+        Err(err) => (StatusCode::SERVICE_UNAVAILABLE, err.to_string()).into_response(),
+    }
+}
+
+// see: https://prometheus.io/docs/instrumenting/exposition_formats/
+async fn metrics<S: Storage + Send + 'static>(
+    State(gateway): Shared<S>,
+) -> Result<Response, ApiError> {
+    let (keys, log_bytes, next_seq) = gateway
+        .store
+        .call(|store| Ok((store.len(), store.storage().len()?, store.next_seq())))
+        .await?;
+
+    let mut body = String::new();
+    let counters = [
+        (
+            "akv_requests_total",
+            "Requests by operation",
+            &gateway.metrics.requests,
+        ),
+        (
+            "akv_errors_total",
+            "Failed requests by operation",
+            &gateway.metrics.errors,
+        ),
+    ];
+    for (name, help, values) in counters {
+        let _ = writeln!(body, "# HELP {name} {help}\n# TYPE {name} counter");
+        for (op, value) in OPS.iter().zip(values) {
+            let _ = writeln!(
+                body,
+                "{name}{{op=\"{op}\"}} {}",
+                value.load(Ordering::Relaxed)
+            );
+        }
+    }
+    let gauges = [
+        ("akv_keys", "Live keys in the store", keys as u64),
+        ("akv_log_bytes", "Size of the log in bytes", log_bytes),
+        (
+            "akv_next_seq",
+            "Sequence number of the next write",
+            next_seq,
+        ),
+    ];
+    for (name, help, value) in gauges {
+        let _ = writeln!(
+            body,
+            "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}"
+        );
+    }
+
+    Ok(([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body).into_response())
+}
+
+#[cfg(test)]
+mod http_test {
+    use std::net::SocketAddr;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::storage::MemStorage;
+    use crate::ActionKV;
+
+    async fn request(addr: SocketAddr, method: &str, path: &str, body: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "{method} {path} HTTP/1.1\r\nHost: localhost\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let status = response[9..12].parse().unwrap();
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        (status, body.to_string())
+    }
+
+    #[tokio::test]
+    async fn rest_roundtrip() {
+        let store = AsyncActionKV::new(ActionKV::with_storage(MemStorage::new()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router(store)).await });
+
+        assert_eq!(
+            request(addr, "GET", "/health", "").await,
+            (200, "ok\n".into())
+        );
+        assert_eq!(request(addr, "PUT", "/kv/user/1", "ada").await.0, 204);
+        assert_eq!(request(addr, "PUT", "/kv/user%202", "bob").await.0, 204);
+        assert_eq!(
+            request(addr, "GET", "/kv/user/1", "").await,
+            (200, "ada".into())
+        );
+        assert_eq!(
+            request(addr, "GET", "/kv?prefix=user", "").await.1,
+            r#"[{"key":"user 2","value":"bob"},{"key":"user/1","value":"ada"}]"#
+        );
+
+        assert_eq!(request(addr, "DELETE", "/kv/user/1", "").await.0, 204);
+        let (status, body) = request(addr, "GET", "/kv/user/1", "").await;
+        assert_eq!(status, 404);
+        assert!(body.contains("does not exist"));
+
+        let (status, body) = request(addr, "GET", "/metrics", "").await;
+        assert_eq!(status, 200);
+        assert!(body.contains("akv_requests_total{op=\"get\"} 2"));
+        assert!(body.contains("akv_errors_total{op=\"get\"} 1"));
+        assert!(body.contains("akv_keys 1"));
+    }
+}
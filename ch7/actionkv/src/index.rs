@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::mem::size_of;
+
+use clap::ValueEnum;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::checksum::{fnv1a_hash, FNV_OFFSET_BASIS};
+use crate::error::Result;
+use crate::{ByteStr, ByteString};
+
+/// How the in-memory index remembers keys
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum IndexMode {
+    /// Keep every full key in memory
+    #[default]
+    Full,
+    /// Keep only a 64-bit hash of each key, verify the full key on read
+    Hashed,
+}
+
+/// Maps keys to the offset of their latest record.
+///
+/// In hashed mode two keys can share a hash, so lookups take a `key_at`
+/// callback that reads the key of the record at an offset to tell them apart.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Index {
+    Full(HashMap<ByteString, u64>),
+    Hashed(HashedIndex),
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct HashedIndex {
+    // hashes shared by a single key, which is nearly all of them
+    offsets: HashMap<u64, u64>,
+    // hashes shared by several keys
+    collisions: HashMap<u64, Vec<u64>>,
+}
+
+fn key_hash(key: &ByteStr) -> u64 {
+    fnv1a_hash(FNV_OFFSET_BASIS, key)
+}
+
+impl Index {
+    pub fn new(mode: IndexMode) -> Self {
+        match mode {
+            IndexMode::Full => Index::Full(HashMap::new()),
+            IndexMode::Hashed => Index::Hashed(HashedIndex::default()),
+        }
+    }
+
+    pub fn mode(&self) -> IndexMode {
+        match self {
+            Index::Full(_) => IndexMode::Full,
+            Index::Hashed(_) => IndexMode::Hashed,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Index::Full(index) => index.len(),
+            Index::Hashed(index) => {
+                index.offsets.len() + index.collisions.values().map(Vec::len).sum::<usize>()
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&mut self) {
+        *self = Index::new(self.mode());
+    }
+
+    /// Rough number of bytes the index takes up in memory, counting the
+    /// table slots and the keys or collision lists they own
+    pub fn memory_estimate(&self) -> usize {
+        // hashbrown keeps one control byte per slot
+        fn table<K, V>(map: &HashMap<K, V>) -> usize {
+            map.capacity() * (size_of::<(K, V)>() + 1)
+        }
+        match self {
+            Index::Full(index) => table(index) + index.keys().map(Vec::capacity).sum::<usize>(),
+            Index::Hashed(index) => {
+                table(&index.offsets)
+                    + table(&index.collisions)
+                    + index
+                        .collisions
+                        .values()
+                        .map(|positions| positions.capacity() * size_of::<u64>())
+                        .sum::<usize>()
+            }
+        }
+    }
+
+    /// Keys held in memory, hashed mode only keeps their hashes
+    pub fn keys(&self) -> Option<impl Iterator<Item = &ByteStr>> {
+        match self {
+            Index::Full(index) => Some(index.keys().map(Vec::as_slice)),
+            Index::Hashed(_) => None,
+        }
+    }
+
+    /// Offsets of the records whose keys may start with `prefix`.
+    /// Hashed mode cannot tell from the hash, so it returns every offset.
+    pub fn scan_positions(&self, prefix: &ByteStr) -> Vec<u64> {
+        match self {
+            Index::Full(index) => index
+                .iter()
+                .filter(|(key, _)| key.starts_with(prefix))
+                .map(|(_, &position)| position)
+                .collect(),
+            Index::Hashed(index) => index
+                .offsets
+                .values()
+                .chain(index.collisions.values().flatten())
+                .copied()
+                .collect(),
+        }
+    }
+
+    pub fn get<F>(&self, key: &ByteStr, mut key_at: F) -> Result<Option<u64>>
+    where
+        F: FnMut(u64) -> Result<ByteString>,
+    {
+        let index = match self {
+            Index::Full(index) => return Ok(index.get(key).copied()),
+            Index::Hashed(index) => index,
+        };
+
+        let hash = key_hash(key);
+        if let Some(&position) = index.offsets.get(&hash) {
+            return Ok((key_at(position)? == key).then_some(position));
+        }
+        for &position in index.collisions.get(&hash).into_iter().flatten() {
+            if key_at(position)? == key {
+                return Ok(Some(position));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn insert<F>(&mut self, key: &ByteStr, position: u64, mut key_at: F) -> Result<()>
+    where
+        F: FnMut(u64) -> Result<ByteString>,
+    {
+        let index = match self {
+            Index::Full(index) => {
+                index.insert(key.to_vec(), position);
+                return Ok(());
+            }
+            Index::Hashed(index) => index,
+        };
+
+        let hash = key_hash(key);
+        if let Some(&old) = index.offsets.get(&hash) {
+            if key_at(old)? == key {
+                index.offsets.insert(hash, position);
+            } else {
+                index.offsets.remove(&hash);
+                index.collisions.insert(hash, vec![old, position]);
+            }
+        } else if let Some(positions) = index.collisions.get_mut(&hash) {
+            for old in positions.iter_mut() {
+                if key_at(*old)? == key {
+                    *old = position;
+                    return Ok(());
+                }
+            }
+            positions.push(position);
+        } else {
+            index.offsets.insert(hash, position);
+        }
+        Ok(())
+    }
+
+    pub fn remove<F>(&mut self, key: &ByteStr, mut key_at: F) -> Result<Option<u64>>
+    where
+        F: FnMut(u64) -> Result<ByteString>,
+    {
+        let index = match self {
+            Index::Full(index) => return Ok(index.remove(key)),
+            Index::Hashed(index) => index,
+        };
+
+        let hash = key_hash(key);
+        if let Some(&position) = index.offsets.get(&hash) {
+            if key_at(position)? == key {
+                return Ok(index.offsets.remove(&hash));
+            }
+            return Ok(None);
+        }
+        let Some(positions) = index.collisions.get_mut(&hash) else {
+            return Ok(None);
+        };
+        for i in 0..positions.len() {
+            if key_at(positions[i])? == key {
+                let position = positions.swap_remove(i);
+                if positions.len() == 1 {
+                    index.offsets.insert(hash, positions[0]);
+                    index.collisions.remove(&hash);
+                }
+                return Ok(Some(position));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod index_test {
+    use super::*;
+
+    #[test]
+    fn hashed_verifies_full_key() {
+        let records: HashMap<u64, ByteString> = [(0, b"a".to_vec())].into();
+        let key_at = |p: u64| Ok(records[&p].clone());
+
+        let mut index = Index::new(IndexMode::Hashed);
+        index.insert(b"a", 0, key_at).unwrap();
+        assert_eq!(index.get(b"a", key_at).unwrap(), Some(0));
+        assert_eq!(index.remove(b"b", key_at).unwrap(), None);
+        assert_eq!(index.remove(b"a", key_at).unwrap(), Some(0));
+        assert!(index.is_empty());
+    }
+
+    #[test]
+    fn hashed_collisions() {
+        // pretend "x" was stored under the same hash as "a"
+        let records: HashMap<u64, ByteString> =
+            [(0, b"x".to_vec()), (10, b"a".to_vec()), (20, b"a".to_vec())].into();
+        let key_at = |p: u64| Ok(records[&p].clone());
+
+        let mut hashed = HashedIndex::default();
+        hashed.collisions.insert(key_hash(b"a"), vec![0, 10]);
+        let mut index = Index::Hashed(hashed);
+
+        assert_eq!(index.get(b"a", key_at).unwrap(), Some(10));
+        index.insert(b"a", 20, key_at).unwrap();
+        assert_eq!(index.get(b"a", key_at).unwrap(), Some(20));
+        assert_eq!(index.len(), 2);
+
+        assert_eq!(index.remove(b"a", key_at).unwrap(), Some(20));
+        assert_eq!(index.get(b"a", key_at).unwrap(), None);
+        assert_eq!(index.len(), 1);
+    }
+}
@@ -1,146 +1,936 @@
+#[cfg(feature = "async")]
+pub mod async_kv;
+pub mod bloom;
 pub mod checksum;
+pub mod error;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod index;
+pub mod merge;
+pub mod record;
+pub mod storage;
 pub mod utils;
 
-use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
-use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::io::{Error, ErrorKind, Result};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
 use std::path::Path;
 
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use serde_derive::{Deserialize, Serialize};
+use tracing::{debug, warn};
 
-use checksum::crc32_checksum;
+use bloom::{BloomFilter, BloomStats};
+pub use error::{ActionKvError, Result};
+use index::{Index, IndexMode};
+use merge::MergeOperator;
+use record::{merge_operand, split_merge_operand, Record, RecordHeader, RecordKind};
+use storage::{FileStorage, Storage, StorageReader};
 
 type ByteString = Vec<u8>;
 
 type ByteStr = [u8];
 
+/// Keys the disk index tools keep their index and bloom filter under
+pub(crate) const INDEX_KEY: &str = "+index+";
+pub(crate) const BLOOM_KEY: &str = "+bloom+";
+
+/// Records of the store itself rather than of its users
+pub(crate) fn is_internal_key(key: &ByteStr) -> bool {
+    key == INDEX_KEY.as_bytes() || key == BLOOM_KEY.as_bytes()
+}
+
+/// Largest keys and values the store accepts, in bytes.
+///
+/// Values over `u32::MAX` bytes are written with 64-bit lengths, raise
+/// `max_value_len` above the default to allow them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub max_key_len: u64,
+    pub max_value_len: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_key_len: 64 * 1024,
+            max_value_len: u32::MAX as u64,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct KeyValuePair {
     pub key: ByteString,
     pub value: ByteString,
 }
 
-pub struct ActionKV {
-    f: File,
-    index: HashMap<ByteString, u64>,
+/// A past value of a key found in the log
+#[derive(Debug)]
+pub struct Version {
+    pub seq: Option<u64>,
+    pub position: u64,
+    /// `None` when the key was deleted
+    pub value: Option<ByteString>,
+}
+
+/// A live key and where its latest record is
+#[derive(Debug)]
+pub struct KeyEntry {
+    pub key: ByteString,
+    pub position: u64,
+    /// Bytes of the record, header included
+    pub len: u64,
+}
+
+/// How the log is used, see `ActionKV::stats`
+#[derive(Debug, Default)]
+pub struct StoreStats {
+    pub records: u64,
+    pub tombstones: u64,
+    pub live_keys: u64,
+    pub file_bytes: u64,
+    /// Bytes of replaced values, tombstones and anything past the last
+    /// readable record
+    pub dead_bytes: u64,
+    pub avg_key_len: f64,
+    pub avg_value_len: f64,
+    /// (key, value length) of the largest live values, largest first
+    pub largest_values: Vec<(ByteString, u64)>,
+    pub index_bytes: usize,
+}
+
+pub struct ActionKV<S: Storage = FileStorage> {
+    storage: S,
+    index: Index,
+    bloom: Option<BloomFilter>,
+    bloom_stats: BloomStats,
+    next_seq: u64,
+    retention: Option<u64>,
+    limits: Limits,
+    merge_operator: Option<Box<dyn MergeOperator>>,
 }
 
 impl ActionKV {
+    /// Open the file at `path` and take an exclusive lock on it,
+    /// fails with `ActionKvError::Locked` if another process holds one
     pub fn open(path: &Path) -> Result<Self> {
-        eprintln!("Open file: {path:?}");
-        let f = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .append(true)
-            .open(path)?;
-        let index = HashMap::new();
-        Ok(Self { f, index })
+        debug!("open file: {path:?}");
+        Ok(ActionKV::with_storage(FileStorage::open(path)?))
+    }
+}
+
+impl<S: Storage> ActionKV<S> {
+    pub fn with_storage(storage: S) -> Self {
+        Self {
+            storage,
+            index: Index::new(IndexMode::Full),
+            bloom: None,
+            bloom_stats: BloomStats::default(),
+            next_seq: 1,
+            retention: None,
+            limits: Limits::default(),
+            merge_operator: None,
+        }
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    pub fn into_storage(self) -> S {
+        self.storage
+    }
+
+    /// Check keys against a bloom filter before the index.
+    /// The filter is rebuilt by `load`, so enable it before loading,
+    /// and grows past `capacity` with the store.
+    pub fn enable_bloom_filter(&mut self, capacity: usize, fp_rate: f64) {
+        self.bloom = Some(BloomFilter::new(capacity, fp_rate));
+    }
+
+    pub fn bloom_filter(&self) -> Option<&BloomFilter> {
+        self.bloom.as_ref()
+    }
+
+    pub fn bloom_stats(&self) -> &BloomStats {
+        &self.bloom_stats
+    }
+
+    /// Rebuild the bloom filter with room for twice the live keys once the
+    /// store holds more than it was sized for, a full filter passes nearly
+    /// every missing key on to the index
+    pub fn fit_bloom_filter(&mut self) -> Result<()> {
+        let Some(bloom) = &self.bloom else {
+            return Ok(());
+        };
+        if self.index.len() <= bloom.capacity() {
+            return Ok(());
+        }
+        let mut resized = BloomFilter::new(self.index.len() * 2, bloom.fp_rate());
+
+        // the keys of replaced and deleted records only add false positives
+        let mut f = BufReader::new(StorageReader::new(&self.storage));
+        loop {
+            let position = f.stream_position()?;
+            let record = match Self::process_record(&mut f, position) {
+                Ok(record) => record,
+                Err(err) if err.is_eof() => break,
+                Err(err) => return Err(err),
+            };
+            if record.kind != RecordKind::Tombstone {
+                resized.insert(&record.kv.key);
+            }
+        }
+        self.bloom = Some(resized);
+        Ok(())
+    }
+
+    /// Choose how keys are kept in memory, `IndexMode::Hashed` bounds the
+    /// index to a hash and an offset per key. Call before `load`.
+    pub fn set_index_mode(&mut self, mode: IndexMode) {
+        self.index = Index::new(mode);
+    }
+
+    pub fn index_mode(&self) -> IndexMode {
+        self.index.mode()
+    }
+
+    /// Number of live keys
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Keep the versions written in the last `window` sequence numbers when
+    /// compacting, `None` keeps only the latest value of each key
+    pub fn set_retention(&mut self, window: Option<u64>) {
+        self.retention = window;
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// Register the operator that `merge` operands are folded with.
+    /// A store holding merge records cannot read them back without one.
+    pub fn set_merge_operator(&mut self, operator: Box<dyn MergeOperator>) {
+        self.merge_operator = Some(operator);
+    }
+
+    /// Sequence number the next record will be written with
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
     }
 
     pub fn load(&mut self) -> Result<()> {
-        let mut f = BufReader::new(&self.f);
+        let mut f = BufReader::new(StorageReader::new(&self.storage));
+        self.index.clear();
+        if let Some(bloom) = &mut self.bloom {
+            bloom.clear();
+        }
 
         loop {
             let position = f.stream_position()?;
 
-            let kv = match ActionKV::process_record(&mut f) {
-                Ok(kv) => kv,
+            let record = match Self::process_record(&mut f, position) {
+                Ok(record) => record,
                 Err(err) => {
-                    if let io::ErrorKind::UnexpectedEof = err.kind() {
+                    if err.is_eof() {
                         break;
                     }
                     return Err(err);
                 }
             };
-
-            self.index.insert(kv.key, position);
+            if let Some(seq) = record.seq {
+                // no record can follow the last sequence number
+                let next = seq
+                    .checked_add(1)
+                    .ok_or(ActionKvError::Corruption { offset: position })?;
+                self.next_seq = self.next_seq.max(next);
+            }
+
+            let key_at = |p| Self::key_at(&self.storage, p);
+            match record.kind {
+                RecordKind::Value | RecordKind::Merge => {
+                    if let Some(bloom) = &mut self.bloom {
+                        bloom.insert(&record.kv.key);
+                    }
+                    self.index.insert(&record.kv.key, position, key_at)?;
+                }
+                RecordKind::Tombstone => {
+                    self.index.remove(&record.kv.key, key_at)?;
+                }
+            }
         }
-        Ok(())
+        self.fit_bloom_filter()
     }
 
     pub fn get(&self, key: &ByteStr) -> Result<Option<ByteString>> {
-        if let Some(&position) = self.index.get(key) {
-            let mut f = BufReader::new(&self.f);
+        if let Some(bloom) = &self.bloom {
+            if !bloom.contains(key) {
+                self.bloom_stats.record_negative();
+                return Ok(None);
+            }
+        }
+        let position = self.index_get(key)?;
+        if self.bloom.is_some() {
+            self.bloom_stats.record_positive(position.is_some());
+        }
+
+        if let Some(position) = position {
+            let mut f = BufReader::new(StorageReader::new(&self.storage));
             f.seek(SeekFrom::Start(position))?;
-            let kv = ActionKV::process_record(&mut f)?;
-            Ok(Some(kv.value.to_vec()))
+            let record = Self::process_record(&mut f, position)?;
+            Ok(Some(self.resolve(record, position)?))
         } else {
             Ok(None)
         }
     }
 
+    /// Value of `key` as of sequence number `seq`, found by scanning the log.
+    /// Records written before sequence numbers existed count as sequence 0.
+    pub fn get_at(&self, key: &ByteStr, seq: u64) -> Result<Option<ByteString>> {
+        let mut value = None;
+        for version in self.history(key)? {
+            if version.seq.unwrap_or(0) > seq {
+                break;
+            }
+            value = version.value;
+        }
+        Ok(value)
+    }
+
+    /// Every version of `key` still in the log, oldest first
+    pub fn history(&self, key: &ByteStr) -> Result<Vec<Version>> {
+        let mut f = BufReader::new(StorageReader::new(&self.storage));
+
+        let mut versions: Vec<Version> = vec![];
+        loop {
+            let position = f.stream_position()?;
+            let record = match Self::process_record(&mut f, position) {
+                Ok(record) => record,
+                Err(err) if err.is_eof() => break,
+                Err(err) => return Err(err),
+            };
+            if record.kv.key == key {
+                let value = match record.kind {
+                    RecordKind::Value => Some(record.kv.value),
+                    RecordKind::Tombstone => None,
+                    RecordKind::Merge => {
+                        let existing = versions.last().and_then(|v| v.value.as_deref());
+                        let (_, operand) = split_merge_operand(&record.kv.value)
+                            .ok_or(ActionKvError::Corruption { offset: position })?;
+                        Some(
+                            self.merge_operator()?
+                                .full_merge(key, existing, &[operand.to_vec()]),
+                        )
+                    }
+                };
+                versions.push(Version {
+                    seq: record.seq,
+                    position,
+                    value,
+                });
+            }
+        }
+        Ok(versions)
+    }
+
+    /// Live key-value pairs whose keys start with `prefix`, sorted by key
+    pub fn scan(&self, prefix: &ByteStr) -> Result<Vec<KeyValuePair>> {
+        let mut f = BufReader::new(StorageReader::new(&self.storage));
+        let mut pairs = vec![];
+        for position in self.index.scan_positions(prefix) {
+            f.seek(SeekFrom::Start(position))?;
+            let record = Self::process_record(&mut f, position)?;
+            if record.kv.key.starts_with(prefix) {
+                let key = record.kv.key.clone();
+                let value = self.resolve(record, position)?;
+                pairs.push(KeyValuePair { key, value });
+            }
+        }
+        pairs.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(pairs)
+    }
+
+    /// Live keys starting with `prefix` with their records, sorted by key.
+    /// Only record headers and keys are read, not the values. The records
+    /// of the disk index are left out.
+    pub fn keys(&self, prefix: &ByteStr) -> Result<Vec<KeyEntry>> {
+        let mut entries = vec![];
+        for position in self.index.scan_positions(prefix) {
+            let (key, len) = Self::key_and_len_at(&self.storage, position)?;
+            if key.starts_with(prefix) && !is_internal_key(&key) {
+                entries.push(KeyEntry { key, position, len });
+            }
+        }
+        entries.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(entries)
+    }
+
+    /// Walk the whole log to see how much of it is still live, keeping the
+    /// `top` largest values. The records of the disk index are live but
+    /// not counted as keys.
+    pub fn stats(&self, top: usize) -> Result<StoreStats> {
+        let mut f = BufReader::new(StorageReader::new(&self.storage));
+        let mut stats = StoreStats {
+            file_bytes: self.storage.len()?,
+            index_bytes: self.index.memory_estimate(),
+            ..Default::default()
+        };
+
+        let (mut live_bytes, mut key_bytes, mut value_bytes) = (0, 0, 0);
+        // smallest of the largest values on top, so it goes first
+        let mut largest = BinaryHeap::with_capacity(top + 1);
+        loop {
+            let position = f.stream_position()?;
+            let record = match Self::process_record(&mut f, position) {
+                Ok(record) => record,
+                Err(err) if err.is_eof() => break,
+                Err(err) => return Err(err),
+            };
+            stats.records += 1;
+            if record.kind == RecordKind::Tombstone {
+                stats.tombstones += 1;
+                continue;
+            }
+            if self.index_get(&record.kv.key)? != Some(position) {
+                continue;
+            }
+
+            live_bytes += f.stream_position()? - position;
+            if is_internal_key(&record.kv.key) {
+                continue;
+            }
+
+            let value_len = record.kv.value.len() as u64;
+            stats.live_keys += 1;
+            key_bytes += record.kv.key.len() as u64;
+            value_bytes += value_len;
+
+            largest.push(Reverse((value_len, record.kv.key)));
+            if largest.len() > top {
+                largest.pop();
+            }
+        }
+        stats.largest_values = largest
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((len, key))| (key, len))
+            .collect();
+
+        stats.dead_bytes = stats.file_bytes - live_bytes;
+        if stats.live_keys > 0 {
+            stats.avg_key_len = key_bytes as f64 / stats.live_keys as f64;
+            stats.avg_value_len = value_bytes as f64 / stats.live_keys as f64;
+        }
+        Ok(stats)
+    }
+
     pub fn insert(&mut self, key: &ByteStr, value: &ByteStr) -> Result<()> {
-        let mut f = BufWriter::new(&self.f);
+        let position = self.append(RecordKind::Value, key, value)?;
 
-        let key_len = key.len();
-        let value_len = value.len();
+        if let Some(bloom) = &mut self.bloom {
+            bloom.insert(key);
+        }
+        self.index_insert(key, position)
+    }
 
-        let mut buf = ByteString::with_capacity(key_len + value_len);
-        buf.extend_from_slice(key);
-        buf.extend_from_slice(value);
+    /// Append `operand` for `key` without reading its value. The registered
+    /// merge operator folds it in when the key is read or compacted.
+    pub fn merge(&mut self, key: &ByteStr, operand: &ByteStr) -> Result<()> {
+        self.merge_operator()?;
+        let previous = self.index_get(key)?;
+        let position = self.append(RecordKind::Merge, key, &merge_operand(previous, operand))?;
 
-        let checksum = crc32_checksum(&buf);
+        if let Some(bloom) = &mut self.bloom {
+            bloom.insert(key);
+        }
+        self.index_insert(key, position)
+    }
 
-        let position = f.stream_position()?;
+    fn merge_operator(&self) -> Result<&dyn MergeOperator> {
+        self.merge_operator
+            .as_deref()
+            .ok_or(ActionKvError::NoMergeOperator)
+    }
 
-        f.write_u32::<LittleEndian>(checksum)?;
-        f.write_u32::<LittleEndian>(key_len as u32)?;
-        f.write_u32::<LittleEndian>(value_len as u32)?;
-        f.write_all(&buf)?;
+    /// Value of the key as of `record`, which was read at `position`.
+    /// A merge record is folded with the records before it, found by
+    /// following their offsets back to a value, a tombstone or the start.
+    fn resolve(&self, record: Record, position: u64) -> Result<ByteString> {
+        if record.kind != RecordKind::Merge {
+            return Ok(record.kv.value);
+        }
+        let operator = self.merge_operator()?;
+        let key = record.kv.key;
+        let mut f = BufReader::new(StorageReader::new(&self.storage));
 
-        self.index.insert(key.to_vec(), position);
-        Ok(())
+        let mut operands = vec![];
+        let mut existing = None;
+        let (mut record_value, mut position) = (record.kv.value, position);
+        loop {
+            let Some((previous, operand)) = split_merge_operand(&record_value) else {
+                return Err(ActionKvError::Corruption { offset: position });
+            };
+            operands.push(operand.to_vec());
+            let Some(previous) = previous else {
+                break;
+            };
+            // offsets only point back, which also rules out cycles
+            if previous >= position {
+                return Err(ActionKvError::Corruption { offset: position });
+            }
+            f.seek(SeekFrom::Start(previous))?;
+            let record = Self::process_record(&mut f, previous)?;
+            if record.kv.key != key {
+                return Err(ActionKvError::Corruption { offset: previous });
+            }
+            match record.kind {
+                RecordKind::Value => {
+                    existing = Some(record.kv.value);
+                    break;
+                }
+                RecordKind::Tombstone => break,
+                RecordKind::Merge => (record_value, position) = (record.kv.value, previous),
+            }
+        }
+
+        operands.reverse();
+        Ok(operator.full_merge(&key, existing.as_deref(), &operands))
+    }
+
+    fn append(&mut self, kind: RecordKind, key: &ByteStr, value: &ByteStr) -> Result<u64> {
+        let Limits {
+            max_key_len,
+            max_value_len,
+        } = self.limits;
+        if key.len() as u64 > max_key_len {
+            return Err(ActionKvError::KeyTooLarge {
+                len: key.len() as u64,
+                max: max_key_len,
+            });
+        }
+        if value.len() as u64 > max_value_len {
+            return Err(ActionKvError::ValueTooLarge {
+                len: value.len() as u64,
+                max: max_value_len,
+            });
+        }
+        let record = Record {
+            seq: Some(self.next_seq),
+            kind,
+            kv: KeyValuePair {
+                key: key.to_vec(),
+                value: value.to_vec(),
+            },
+        };
+        let next_seq = self
+            .next_seq
+            .checked_add(1)
+            .ok_or(ActionKvError::Corruption {
+                offset: self.storage.len()?,
+            })?;
+        let position = self.storage.append(&record.encode())?;
+
+        self.next_seq = next_seq;
+        Ok(position)
+    }
+
+    /// Make every write so far durable
+    pub fn sync(&mut self) -> Result<()> {
+        Ok(self.storage.sync()?)
     }
 
     pub fn delete(&mut self, key: &ByteStr) -> Result<()> {
-        self.insert(key, b"")?;
-        if self.index.remove(key).is_none() {
-            Err(Error::new(
-                ErrorKind::Other,
-                "{key:?} does not exist in index",
-            ))
-        } else {
-            Ok(())
+        if self.index_get(key)?.is_none() {
+            return Err(ActionKvError::NotFound(key.to_vec()));
         }
+        self.append(RecordKind::Tombstone, key, b"")?;
+        self.index_remove(key)?;
+        Ok(())
     }
 
     pub fn update(&mut self, key: &ByteStr, value: &ByteStr) -> Result<()> {
         self.insert(key, value)
     }
 
-    fn process_record<R: Read>(f: &mut R) -> Result<KeyValuePair> {
-        let saved_checksum = f.read_u32::<LittleEndian>()?;
-        let key_len = f.read_u32::<LittleEndian>()?;
-        let value_len = f.read_u32::<LittleEndian>()?;
+    /// Rewrite the log with only the latest value of each key and the
+    /// records inside the retention window, then reload it. Merge records
+    /// that are kept are written as the values they fold to.
+    pub fn compact(&mut self) -> Result<()> {
+        let oldest_kept = self.retention.map(|w| self.next_seq.saturating_sub(w));
+        let mut compacted = self.storage.start_compaction()?;
+
+        let mut f = BufReader::new(StorageReader::new(&self.storage));
+        loop {
+            let position = f.stream_position()?;
+            let record = match Self::process_record(&mut f, position) {
+                Ok(record) => record,
+                Err(err) if err.is_eof() => break,
+                Err(err) => return Err(err),
+            };
+
+            let live = record.kind != RecordKind::Tombstone
+                && self.index_get(&record.kv.key)? == Some(position);
+
+            let retained = match (oldest_kept, record.seq) {
+                (Some(oldest), Some(seq)) => seq >= oldest,
+                _ => false,
+            };
+            if !(live || retained) {
+                continue;
+            }
+            let record = match record.kind {
+                RecordKind::Merge => Record {
+                    seq: record.seq,
+                    kind: RecordKind::Value,
+                    kv: KeyValuePair {
+                        key: record.kv.key.clone(),
+                        value: self.resolve(record, position)?,
+                    },
+                },
+                _ => record,
+            };
+            compacted.append(&record.encode())?;
+        }
+
+        self.storage.finish_compaction(compacted)?;
+        self.load()
+    }
+
+    pub(crate) fn index_get(&self, key: &ByteStr) -> Result<Option<u64>> {
+        self.index.get(key, |p| Self::key_at(&self.storage, p))
+    }
+
+    pub(crate) fn index_insert(&mut self, key: &ByteStr, position: u64) -> Result<()> {
+        self.index
+            .insert(key, position, |p| Self::key_at(&self.storage, p))
+    }
+
+    pub(crate) fn index_remove(&mut self, key: &ByteStr) -> Result<Option<u64>> {
+        self.index.remove(key, |p| Self::key_at(&self.storage, p))
+    }
+
+    /// Read only the key of the record at `position`
+    fn key_at(storage: &S, position: u64) -> Result<ByteString> {
+        Ok(Self::key_and_len_at(storage, position)?.0)
+    }
+
+    /// Key of the record at `position` and the bytes the record takes up
+    fn key_and_len_at(storage: &S, position: u64) -> Result<(ByteString, u64)> {
+        let mut f = BufReader::new(StorageReader::new(storage));
+        f.seek(SeekFrom::Start(position))?;
+        let header = RecordHeader::read(&mut f)?;
+
+        let mut key = ByteString::new();
+        f.by_ref().take(header.key_len).read_to_end(&mut key)?;
+        Ok((key, header.record_len()))
+    }
+
+    /// Decode the record that starts at `offset` of the file `f` reads.
+    /// Public only for the fuzz targets.
+    #[doc(hidden)]
+    pub fn process_record<R: Read>(f: &mut R, offset: u64) -> Result<Record> {
+        let header = RecordHeader::read(f)?;
 
-        let data_len = key_len + value_len;
-        let mut buf = ByteString::with_capacity(data_len as usize);
+        // lengths come from the file, so grow the buffer with the data
+        // actually read instead of trusting them for the allocation
+        let Some(data_len) = header.key_len.checked_add(header.value_len) else {
+            return Err(ActionKvError::Corruption { offset });
+        };
+        let mut buf = ByteString::new();
 
-        f.by_ref().take(data_len as u64).read_to_end(&mut buf)?;
+        f.by_ref().take(data_len).read_to_end(&mut buf)?;
 
-        debug_assert_eq!(buf.len(), data_len as usize);
+        if buf.len() as u64 != data_len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
 
-        let checksum = crc32_checksum(&buf);
+        let checksum = header.checksum_of(&buf);
 
-        if saved_checksum != checksum {
-            panic!(
-                "data corruption encountered ({:08x} != {:08x})",
-                checksum, saved_checksum
+        if header.checksum != checksum {
+            warn!(
+                "data corruption encountered at {offset} ({:08x} != {:08x})",
+                checksum, header.checksum
             );
+            return Err(ActionKvError::Corruption { offset });
         }
 
-        let value = buf.split_off(key_len as usize);
+        let value = buf.split_off(header.key_len as usize);
         let key = buf;
 
-        Ok(KeyValuePair { key, value })
+        Ok(Record {
+            seq: header.seq,
+            kind: header.kind,
+            kv: KeyValuePair { key, value },
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use storage::{FaultyStorage, MemStorage};
+
+    fn mem_store() -> ActionKV<MemStorage> {
+        ActionKV::with_storage(MemStorage::new())
+    }
+
+    #[test]
+    fn versions_and_compaction() {
+        let mut store = mem_store();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"a", b"2").unwrap();
+        store.insert(b"b", b"3").unwrap();
+        store.delete(b"b").unwrap();
+
+        assert_eq!(store.get_at(b"a", 1).unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get_at(b"b", 3).unwrap(), Some(b"3".to_vec()));
+        assert_eq!(store.get_at(b"b", 4).unwrap(), None);
+        assert_eq!(store.history(b"a").unwrap().len(), 2);
+
+        store.set_retention(Some(2));
+        store.compact().unwrap();
+        assert_eq!(store.history(b"a").unwrap().len(), 1);
+        assert_eq!(store.history(b"b").unwrap().len(), 2);
+        assert_eq!(store.next_seq(), 5);
+
+        store.set_retention(None);
+        store.compact().unwrap();
+        assert!(store.history(b"b").unwrap().is_empty());
+        assert_eq!(store.get(b"a").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn scan_prefix() {
+        for mode in [IndexMode::Full, IndexMode::Hashed] {
+            let mut store = mem_store();
+            store.set_index_mode(mode);
+            store.insert(b"user:2", b"b").unwrap();
+            store.insert(b"user:1", b"a").unwrap();
+            store.insert(b"group:1", b"c").unwrap();
+            store.delete(b"user:2").unwrap();
+
+            let keys: Vec<_> = store
+                .scan(b"user:")
+                .unwrap()
+                .into_iter()
+                .map(|kv| kv.key)
+                .collect();
+            assert_eq!(keys, vec![b"user:1".to_vec()]);
+            assert_eq!(store.scan(b"").unwrap().len(), 2);
+        }
+    }
+
+    #[test]
+    fn keys_and_stats() {
+        for mode in [IndexMode::Full, IndexMode::Hashed] {
+            let mut store = mem_store();
+            store.set_index_mode(mode);
+            store.insert(b"a", b"1").unwrap();
+            store.insert(b"a", b"22").unwrap();
+            let second = store.storage.len().unwrap();
+            store.insert(b"bb", b"4444").unwrap();
+            store.insert(b"c", b"").unwrap();
+            store.delete(b"c").unwrap();
+
+            let keys = store.keys(b"").unwrap();
+            assert_eq!(keys.len(), 2);
+            assert_eq!(
+                (keys[0].key.as_slice(), keys[0].position),
+                (&b"a"[..], second - 27)
+            );
+            assert_eq!(keys[1].len, 24 + 2 + 4);
+            assert_eq!(store.keys(b"b").unwrap().len(), 1);
+
+            let stats = store.stats(1).unwrap();
+            assert_eq!(
+                (stats.records, stats.tombstones, stats.live_keys),
+                (5, 1, 2)
+            );
+            assert_eq!(stats.dead_bytes, 26 + 25 + 25);
+            assert_eq!(stats.avg_key_len, 1.5);
+            assert_eq!(stats.avg_value_len, 3.0);
+            assert_eq!(stats.largest_values, vec![(b"bb".to_vec(), 4)]);
+            assert!(stats.index_bytes > 0);
+            let stats = store.stats(5).unwrap();
+            assert_eq!(
+                stats.largest_values,
+                vec![(b"bb".to_vec(), 4), (b"a".to_vec(), 2)]
+            );
+
+            // what the disk index tools write is no user data
+            store.insert(INDEX_KEY.as_bytes(), b"index").unwrap();
+            assert_eq!(store.keys(b"").unwrap().len(), 2);
+            let stats = store.stats(1).unwrap();
+            assert_eq!((stats.live_keys, stats.dead_bytes), (2, 26 + 25 + 25));
+            assert_eq!(stats.largest_values, vec![(b"bb".to_vec(), 4)]);
+        }
+    }
+
+    #[test]
+    fn merge_folds_on_get_and_compaction() {
+        for mode in [IndexMode::Full, IndexMode::Hashed] {
+            let mut store = mem_store();
+            store.set_index_mode(mode);
+            assert!(matches!(
+                store.merge(b"list", b"a"),
+                Err(ActionKvError::NoMergeOperator)
+            ));
+            store.set_merge_operator(Box::new(merge::Append {
+                delimiter: b",".to_vec(),
+            }));
+
+            store.merge(b"list", b"a").unwrap();
+            store.insert(b"other", b"x").unwrap();
+            store.merge(b"list", b"b").unwrap();
+            assert_eq!(store.get(b"list").unwrap(), Some(b"a,b".to_vec()));
+
+            store.delete(b"list").unwrap();
+            store.merge(b"list", b"c").unwrap();
+            store.insert(b"base", b"0").unwrap();
+            store.merge(b"base", b"1").unwrap();
+            store.merge(b"base", b"2").unwrap();
+            assert_eq!(store.get(b"list").unwrap(), Some(b"c".to_vec()));
+            assert_eq!(store.get(b"base").unwrap(), Some(b"0,1,2".to_vec()));
+            assert_eq!(store.scan(b"b").unwrap()[0].value, b"0,1,2");
+            assert_eq!(store.get_at(b"base", 7).unwrap(), Some(b"0,1".to_vec()));
+
+            store.set_retention(Some(2));
+            store.compact().unwrap();
+            assert_eq!(store.get(b"base").unwrap(), Some(b"0,1,2".to_vec()));
+            assert_eq!(store.get_at(b"base", 7).unwrap(), Some(b"0,1".to_vec()));
+            let mut f = store.storage.as_bytes();
+            while let Ok(record) = ActionKV::<MemStorage>::process_record(&mut f, 0) {
+                assert_ne!(record.kind, RecordKind::Merge);
+            }
+
+            store.load().unwrap();
+            assert_eq!(store.get(b"list").unwrap(), Some(b"c".to_vec()));
+        }
+    }
+
+    #[test]
+    fn size_limits() {
+        let mut store = mem_store();
+        store.set_limits(Limits {
+            max_key_len: 4,
+            max_value_len: 8,
+        });
+
+        assert!(matches!(
+            store.insert(b"too long", b""),
+            Err(ActionKvError::KeyTooLarge { len: 8, max: 4 })
+        ));
+        assert!(matches!(
+            store.insert(b"key", b"too long value"),
+            Err(ActionKvError::ValueTooLarge { len: 14, max: 8 })
+        ));
+        store.insert(b"key", b"value").unwrap();
+        assert_eq!(store.next_seq(), 2);
+    }
+
+    #[test]
+    fn load_stops_at_torn_write() {
+        let mut store = mem_store();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+        // a crash in the middle of the second record
+        let mut data = store.into_storage().into_bytes();
+        data.truncate(data.len() - 10);
+
+        let mut store = ActionKV::with_storage(MemStorage::from_bytes(data));
+        store.load().unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), None);
+    }
+
+    #[test]
+    fn failed_append_leaves_no_torn_record() {
+        let mut store = ActionKV::with_storage(FaultyStorage::new(MemStorage::new()));
+        store.insert(b"a", b"1").unwrap();
+        store.storage.short_write_next(10);
+        assert!(store.insert(b"b", b"2").is_err());
+        store.insert(b"c", b"3").unwrap();
+
+        let mut store = ActionKV::with_storage(store.into_storage().into_inner());
+        store.load().unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), None);
+        assert_eq!(store.get(b"c").unwrap(), Some(b"3".to_vec()));
+    }
+
+    #[test]
+    fn bloom_filter_grows_with_the_store() {
+        let mut store = mem_store();
+        for i in 0..100 {
+            store.insert(format!("key{i}").as_bytes(), b"v").unwrap();
+        }
+        store.enable_bloom_filter(10, 0.01);
+        store.load().unwrap();
+
+        let bloom = store.bloom_filter().unwrap();
+        assert_eq!(bloom.capacity(), 200);
+        assert!((0..100).all(|i| bloom.contains(format!("key{i}").as_bytes())));
+        let false_positives = (0..1000)
+            .filter(|i| bloom.contains(format!("missing{i}").as_bytes()))
+            .count();
+        assert!(false_positives < 50, "{false_positives} false positives");
+    }
+
+    #[test]
+    fn last_seq_is_corruption() {
+        let mut store = mem_store();
+        store.insert(b"a", b"1").unwrap();
+        let last = store.storage.len().unwrap();
+        let record = Record {
+            seq: Some(u64::MAX),
+            kind: RecordKind::Value,
+            kv: KeyValuePair {
+                key: b"b".to_vec(),
+                value: b"2".to_vec(),
+            },
+        };
+        store.storage.append(&record.encode()).unwrap();
+
+        assert!(matches!(
+            store.load(),
+            Err(ActionKvError::Corruption { offset }) if offset == last
+        ));
+        store.next_seq = u64::MAX;
+        assert!(matches!(
+            store.insert(b"c", b"3"),
+            Err(ActionKvError::Corruption { .. })
+        ));
+    }
+
+    #[test]
+    fn bit_flip_is_corruption() {
+        let mut store = ActionKV::with_storage(FaultyStorage::new(MemStorage::new()));
+        store.insert(b"a", b"1").unwrap();
+        let second = store.storage.len().unwrap();
+        store.insert(b"b", b"2").unwrap();
+        // 24 bytes of header, then the key and the value
+        store.storage.flip_bit(second + 25, 3);
+
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert!(matches!(
+            store.get(b"b"),
+            Err(ActionKvError::Corruption { offset }) if offset == second
+        ));
+        assert!(matches!(
+            store.load(),
+            Err(ActionKvError::Corruption { offset }) if offset == second
+        ));
+    }
 
     #[test]
     fn it_works() {
@@ -0,0 +1,112 @@
+use clap::ValueEnum;
+
+use crate::{ByteStr, ByteString};
+
+/// Folds merge operands into the value of a key, like a RocksDB merge operator.
+///
+/// Operands are written with `ActionKV::merge` without reading the value,
+/// and folded when the key is read or the log is compacted.
+pub trait MergeOperator: Send + Sync {
+    /// Value of `key` after applying `operands`, oldest first, to `existing`,
+    /// which is `None` when the key has no value
+    fn full_merge(
+        &self,
+        key: &ByteStr,
+        existing: Option<&ByteStr>,
+        operands: &[ByteString],
+    ) -> ByteString;
+}
+
+/// Appends each operand to the value, separated by `delimiter`
+#[derive(Debug, Clone)]
+pub struct Append {
+    pub delimiter: ByteString,
+}
+
+impl MergeOperator for Append {
+    fn full_merge(
+        &self,
+        _key: &ByteStr,
+        existing: Option<&ByteStr>,
+        operands: &[ByteString],
+    ) -> ByteString {
+        let mut parts = existing
+            .into_iter()
+            .chain(operands.iter().map(Vec::as_slice));
+        let mut value = parts.next().map(<[u8]>::to_vec).unwrap_or_default();
+        for part in parts {
+            value.extend_from_slice(&self.delimiter);
+            value.extend_from_slice(part);
+        }
+        value
+    }
+}
+
+/// Adds operands to the value, both as decimal integers.
+/// Anything that does not parse counts as 0.
+#[derive(Debug, Clone, Copy)]
+pub struct Counter;
+
+impl MergeOperator for Counter {
+    fn full_merge(
+        &self,
+        _key: &ByteStr,
+        existing: Option<&ByteStr>,
+        operands: &[ByteString],
+    ) -> ByteString {
+        let parse = |bytes: &ByteStr| -> i64 {
+            std::str::from_utf8(bytes)
+                .ok()
+                .and_then(|s| s.trim().parse().ok())
+                .unwrap_or(0)
+        };
+        let total = operands
+            .iter()
+            .fold(existing.map_or(0, parse), |total, operand| {
+                total.wrapping_add(parse(operand))
+            });
+        total.to_string().into_bytes()
+    }
+}
+
+/// Merge operators the CLI can register
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum BuiltinMerge {
+    /// Append operands to the value, one per line
+    Append,
+    /// Add operands to the value as integers
+    Counter,
+}
+
+impl BuiltinMerge {
+    pub fn operator(self) -> Box<dyn MergeOperator> {
+        match self {
+            BuiltinMerge::Append => Box::new(Append {
+                delimiter: b"\n".to_vec(),
+            }),
+            BuiltinMerge::Counter => Box::new(Counter),
+        }
+    }
+}
+
+#[cfg(test)]
+mod merge_test {
+    use super::*;
+
+    #[test]
+    fn append() {
+        let append = Append {
+            delimiter: b",".to_vec(),
+        };
+        let operands = [b"b".to_vec(), b"c".to_vec()];
+        assert_eq!(append.full_merge(b"k", Some(b"a"), &operands), b"a,b,c");
+        assert_eq!(append.full_merge(b"k", None, &operands), b"b,c");
+    }
+
+    #[test]
+    fn counter() {
+        let operands = [b"2".to_vec(), b"-5".to_vec(), b"x".to_vec()];
+        assert_eq!(Counter.full_merge(b"k", Some(b"10"), &operands), b"7");
+        assert_eq!(Counter.full_merge(b"k", None, &operands[..1]), b"2");
+    }
+}
@@ -0,0 +1,272 @@
+use std::io::{Read, Result};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::checksum::crc32_checksum_parts;
+use crate::{ByteString, KeyValuePair};
+
+// Records written before sequence numbers existed are laid out as
+//
+//   checksum u32 | key_len u32 | value_len u32 | key | value
+//
+// with the checksum over key and value. Records written now are
+//
+//   checksum u32 | flags u32 | seq u64 | key_len u32 | value_len u32 | key | value
+//
+// with the checksum over everything after it. `EXTENDED` is always set in
+// `flags` and never in a legacy key length, which tells the two apart.
+// Records with `WIDE` in `flags` store both lengths as u64.
+//
+// The value of a `MERGE` record is the offset of the previous record of
+// its key as u64, `NO_PREVIOUS` if there is none, followed by the operand.
+const EXTENDED: u32 = 1 << 31;
+const TOMBSTONE: u32 = 1;
+const WIDE: u32 = 1 << 1;
+const MERGE: u32 = 1 << 2;
+const NO_PREVIOUS: u64 = u64::MAX;
+
+/// What a record says about its key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordKind {
+    Value,
+    /// Written by `delete`, the value is empty
+    Tombstone,
+    /// Written by `merge`, the value is an operand for the merge operator,
+    /// see `merge_operand`
+    Merge,
+}
+
+/// Value of a merge record holding `operand`, where `previous` is the
+/// offset of the record of the key it applies to
+pub(crate) fn merge_operand(previous: Option<u64>, operand: &[u8]) -> ByteString {
+    let mut value = ByteString::with_capacity(8 + operand.len());
+    value.extend_from_slice(&previous.unwrap_or(NO_PREVIOUS).to_le_bytes());
+    value.extend_from_slice(operand);
+    value
+}
+
+/// Splits the value of a merge record back into the offset of the previous
+/// record and the operand, `None` if it is too short to hold the offset
+pub(crate) fn split_merge_operand(value: &[u8]) -> Option<(Option<u64>, &[u8])> {
+    let (previous, operand) = value.split_first_chunk::<8>()?;
+    let previous = u64::from_le_bytes(*previous);
+    Some(((previous != NO_PREVIOUS).then_some(previous), operand))
+}
+
+/// A record as stored in the log
+#[derive(Debug)]
+pub struct Record {
+    /// Position of the record in the history of the store,
+    /// `None` for records written before sequence numbers existed
+    pub seq: Option<u64>,
+    pub kind: RecordKind,
+    pub kv: KeyValuePair,
+}
+
+pub(crate) struct RecordHeader {
+    pub(crate) checksum: u32,
+    pub(crate) seq: Option<u64>,
+    pub(crate) kind: RecordKind,
+    pub(crate) key_len: u64,
+    pub(crate) value_len: u64,
+    // bytes after the checksum that the checksum covers
+    raw: ByteString,
+}
+
+impl RecordHeader {
+    pub(crate) fn read<R: Read>(f: &mut R) -> Result<Self> {
+        let checksum = f.read_u32::<LittleEndian>()?;
+        let first = f.read_u32::<LittleEndian>()?;
+
+        if first & EXTENDED == 0 {
+            let value_len = f.read_u32::<LittleEndian>()?;
+            return Ok(Self {
+                checksum,
+                seq: None,
+                kind: RecordKind::Value,
+                key_len: first as u64,
+                value_len: value_len as u64,
+                raw: ByteString::new(),
+            });
+        }
+
+        let seq = f.read_u64::<LittleEndian>()?;
+        let (key_len, value_len) = if first & WIDE != 0 {
+            (f.read_u64::<LittleEndian>()?, f.read_u64::<LittleEndian>()?)
+        } else {
+            (
+                f.read_u32::<LittleEndian>()? as u64,
+                f.read_u32::<LittleEndian>()? as u64,
+            )
+        };
+        let kind = if first & TOMBSTONE != 0 {
+            RecordKind::Tombstone
+        } else if first & MERGE != 0 {
+            RecordKind::Merge
+        } else {
+            RecordKind::Value
+        };
+
+        let mut raw = ByteString::with_capacity(28);
+        raw.write_u32::<LittleEndian>(first)?;
+        raw.write_u64::<LittleEndian>(seq)?;
+        if first & WIDE != 0 {
+            raw.write_u64::<LittleEndian>(key_len)?;
+            raw.write_u64::<LittleEndian>(value_len)?;
+        } else {
+            raw.write_u32::<LittleEndian>(key_len as u32)?;
+            raw.write_u32::<LittleEndian>(value_len as u32)?;
+        }
+
+        Ok(Self {
+            checksum,
+            seq: Some(seq),
+            kind,
+            key_len,
+            value_len,
+            raw,
+        })
+    }
+
+    /// Bytes the whole record takes up in the log, header included
+    pub(crate) fn record_len(&self) -> u64 {
+        // legacy headers are not kept in `raw`
+        let header_len = if self.seq.is_some() {
+            4 + self.raw.len()
+        } else {
+            12
+        };
+        header_len as u64 + self.key_len + self.value_len
+    }
+
+    /// Checksum of the header and the `data` that follows it
+    pub(crate) fn checksum_of(&self, data: &[u8]) -> u32 {
+        crc32_checksum_parts(&[&self.raw, data])
+    }
+}
+
+impl Record {
+    /// The bytes of the record as they are appended to the log.
+    /// Only tombstones and merge records with a sequence number keep their kind.
+    pub fn encode(&self) -> ByteString {
+        let KeyValuePair { key, value } = &self.kv;
+        let mut header = ByteString::with_capacity(28);
+
+        let checksum = match self.seq {
+            None => {
+                header.extend_from_slice(&(key.len() as u32).to_le_bytes());
+                header.extend_from_slice(&(value.len() as u32).to_le_bytes());
+                crc32_checksum_parts(&[key, value])
+            }
+            Some(seq) => {
+                let wide =
+                    key.len() as u64 > u32::MAX as u64 || value.len() as u64 > u32::MAX as u64;
+                let mut flags = EXTENDED;
+                match self.kind {
+                    RecordKind::Value => {}
+                    RecordKind::Tombstone => flags |= TOMBSTONE,
+                    RecordKind::Merge => flags |= MERGE,
+                }
+                if wide {
+                    flags |= WIDE;
+                }
+                header.extend_from_slice(&flags.to_le_bytes());
+                header.extend_from_slice(&seq.to_le_bytes());
+                if wide {
+                    header.extend_from_slice(&(key.len() as u64).to_le_bytes());
+                    header.extend_from_slice(&(value.len() as u64).to_le_bytes());
+                } else {
+                    header.extend_from_slice(&(key.len() as u32).to_le_bytes());
+                    header.extend_from_slice(&(value.len() as u32).to_le_bytes());
+                }
+                crc32_checksum_parts(&[&header, key, value])
+            }
+        };
+
+        let mut buf = ByteString::with_capacity(4 + header.len() + key.len() + value.len());
+        buf.extend_from_slice(&checksum.to_le_bytes());
+        buf.extend_from_slice(&header);
+        buf.extend_from_slice(key);
+        buf.extend_from_slice(value);
+        buf
+    }
+}
+
+#[cfg(test)]
+mod record_test {
+    use super::*;
+    use crate::checksum::crc32_checksum;
+
+    fn record(seq: Option<u64>, kind: RecordKind) -> Record {
+        Record {
+            seq,
+            kind,
+            kv: KeyValuePair {
+                key: b"key".to_vec(),
+                value: b"value".to_vec(),
+            },
+        }
+    }
+
+    #[test]
+    fn legacy_layout() {
+        let buf = record(None, RecordKind::Value).encode();
+        assert_eq!(buf[..4], crc32_checksum(b"keyvalue").to_le_bytes());
+        assert_eq!(buf[4..12], [3, 0, 0, 0, 5, 0, 0, 0]);
+
+        let header = RecordHeader::read(&mut &buf[..]).unwrap();
+        assert_eq!(header.seq, None);
+        assert_eq!(header.checksum, header.checksum_of(b"keyvalue"));
+        assert_eq!(header.record_len(), buf.len() as u64);
+    }
+
+    #[test]
+    fn extended_header_roundtrip() {
+        let buf = record(Some(42), RecordKind::Tombstone).encode();
+        let header = RecordHeader::read(&mut &buf[..]).unwrap();
+        assert_eq!(header.seq, Some(42));
+        assert_eq!(header.kind, RecordKind::Tombstone);
+        assert_eq!((header.key_len, header.value_len), (3, 5));
+        assert_eq!(header.checksum, header.checksum_of(b"keyvalue"));
+        assert_ne!(header.checksum, crc32_checksum(b"keyvalue"));
+        assert_eq!(header.record_len(), buf.len() as u64);
+    }
+
+    #[test]
+    fn merge_operand_roundtrip() {
+        let mut merge = record(Some(3), RecordKind::Merge);
+        merge.kv.value = merge_operand(Some(12), b"op");
+        let buf = merge.encode();
+        assert_eq!(
+            RecordHeader::read(&mut &buf[..]).unwrap().kind,
+            RecordKind::Merge
+        );
+
+        assert_eq!(
+            split_merge_operand(&merge.kv.value),
+            Some((Some(12), &b"op"[..]))
+        );
+        assert_eq!(
+            split_merge_operand(&merge_operand(None, b"")),
+            Some((None, &b""[..]))
+        );
+        assert_eq!(split_merge_operand(b"short"), None);
+    }
+
+    #[test]
+    fn wide_header() {
+        let mut raw = ByteString::new();
+        raw.extend_from_slice(&(EXTENDED | WIDE).to_le_bytes());
+        raw.extend_from_slice(&7_u64.to_le_bytes());
+        raw.extend_from_slice(&3_u64.to_le_bytes());
+        raw.extend_from_slice(&5_u64.to_le_bytes());
+        let checksum = crc32_checksum_parts(&[&raw, b"keyvalue"]);
+
+        let mut buf = checksum.to_le_bytes().to_vec();
+        buf.extend_from_slice(&raw);
+        let header = RecordHeader::read(&mut &buf[..]).unwrap();
+        assert_eq!(header.seq, Some(7));
+        assert_eq!((header.key_len, header.value_len), (3, 5));
+        assert_eq!(header.checksum, header.checksum_of(b"keyvalue"));
+    }
+}
@@ -0,0 +1,374 @@
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use tracing::warn;
+
+use crate::error::{ActionKvError, Result};
+
+/// Append-only log the records of a store live in
+pub trait Storage: Sized {
+    /// Write `buf` at the end of the log, returning the offset it starts at.
+    /// A failed append leaves none of `buf` behind.
+    fn append(&mut self, buf: &[u8]) -> io::Result<u64>;
+
+    /// Read from `offset` into `buf`, returning the number of bytes read,
+    /// which is 0 at the end of the log
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize>;
+
+    fn len(&self) -> io::Result<u64>;
+
+    /// Drop everything from `len` on
+    fn truncate(&mut self, len: u64) -> io::Result<()>;
+
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Make appended bytes durable
+    fn sync(&mut self) -> io::Result<()>;
+
+    /// Empty log that compaction writes into before `finish_compaction`
+    /// swaps it in
+    fn start_compaction(&self) -> Result<Self>;
+
+    fn finish_compaction(&mut self, compacted: Self) -> Result<()>;
+}
+
+/// `Read` and `Seek` over a storage, wrap it in a `BufReader` to decode records
+pub struct StorageReader<'a, S> {
+    storage: &'a S,
+    position: u64,
+}
+
+impl<'a, S: Storage> StorageReader<'a, S> {
+    pub fn new(storage: &'a S) -> Self {
+        Self {
+            storage,
+            position: 0,
+        }
+    }
+}
+
+impl<S: Storage> Read for StorageReader<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.storage.read_at(self.position, buf)?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl<S: Storage> Seek for StorageReader<'_, S> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.storage.len()?.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+/// A file holding an exclusive lock for as long as it is open
+#[derive(Debug)]
+pub struct FileStorage {
+    path: PathBuf,
+    f: File,
+    len: u64,
+}
+
+impl FileStorage {
+    /// Fails with `ActionKvError::Locked` if another process holds the lock
+    pub fn open(path: &Path) -> Result<Self> {
+        let f = OpenOptions::new()
+            .read(true)
+            .create(true)
+            .append(true)
+            .open(path)?;
+        match f.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => return Err(ActionKvError::Locked),
+            Err(TryLockError::Error(err)) => return Err(err.into()),
+        }
+        let len = f.metadata()?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            f,
+            len,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Storage for FileStorage {
+    fn append(&mut self, buf: &[u8]) -> io::Result<u64> {
+        let position = self.len;
+        if let Err(err) = self.f.write_all(buf) {
+            // part of buf may have made it to the file, and load would stop
+            // at it before reaching any record appended after
+            if let Err(truncate_err) = self.truncate(position) {
+                warn!("unable to drop a torn record at {position}: {truncate_err}");
+                self.len = self.f.metadata()?.len();
+            }
+            return Err(err);
+        }
+        self.len += buf.len() as u64;
+        Ok(position)
+    }
+
+    #[cfg(unix)]
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::read_at(&self.f, buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        std::os::windows::fs::FileExt::seek_read(&self.f, buf, offset)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.len)
+    }
+
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.f.set_len(len)?;
+        self.len = len;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.f.sync_data()
+    }
+
+    fn start_compaction(&self) -> Result<Self> {
+        // left over by a compaction that did not finish
+        let path = self.path.with_extension("compact");
+        if path.exists() {
+            fs::remove_file(&path)?;
+        }
+        Self::open(&path)
+    }
+
+    fn finish_compaction(&mut self, mut compacted: Self) -> Result<()> {
+        compacted.sync()?;
+        fs::rename(&compacted.path, &self.path)?;
+        compacted.path = self.path.clone();
+        *self = compacted;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct MemStorage {
+    data: Vec<u8>,
+}
+
+impl MemStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> Self {
+        Self { data }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+impl Storage for MemStorage {
+    fn append(&mut self, buf: &[u8]) -> io::Result<u64> {
+        let position = self.data.len() as u64;
+        self.data.extend_from_slice(buf);
+        Ok(position)
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let start = usize::try_from(offset)
+            .unwrap_or(usize::MAX)
+            .min(self.data.len());
+        let n = buf.len().min(self.data.len() - start);
+        buf[..n].copy_from_slice(&self.data[start..start + n]);
+        Ok(n)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.data.len() as u64)
+    }
+
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.data
+            .truncate(usize::try_from(len).unwrap_or(usize::MAX));
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn start_compaction(&self) -> Result<Self> {
+        Ok(Self::new())
+    }
+
+    fn finish_compaction(&mut self, compacted: Self) -> Result<()> {
+        *self = compacted;
+        Ok(())
+    }
+}
+
+/// Wraps a storage to inject the faults its recovery has to cope with
+#[derive(Debug, Default)]
+pub struct FaultyStorage<S> {
+    inner: S,
+    short_write: Option<usize>,
+    // (offset, mask) pairs xor-ed into every read of the offset
+    bit_flips: Vec<(u64, u8)>,
+}
+
+impl<S: Storage> FaultyStorage<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            short_write: None,
+            bit_flips: vec![],
+        }
+    }
+
+    /// The next append only stores the first `len` bytes and then fails,
+    /// rolling them back like `FileStorage` does after a failed write
+    pub fn short_write_next(&mut self, len: usize) {
+        self.short_write = Some(len);
+    }
+
+    /// Reads of the byte at `offset` come back with `bit` flipped
+    pub fn flip_bit(&mut self, offset: u64, bit: u8) {
+        self.bit_flips.push((offset, 1 << (bit % 8)));
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: Storage> Storage for FaultyStorage<S> {
+    fn append(&mut self, buf: &[u8]) -> io::Result<u64> {
+        match self.short_write.take() {
+            Some(len) => {
+                let position = self.inner.append(&buf[..len.min(buf.len())])?;
+                self.inner.truncate(position)?;
+                Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "injected short write",
+                ))
+            }
+            None => self.inner.append(buf),
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read_at(offset, buf)?;
+        for &(flip_offset, mask) in &self.bit_flips {
+            if let Some(i) = flip_offset.checked_sub(offset) {
+                if i < n as u64 {
+                    buf[i as usize] ^= mask;
+                }
+            }
+        }
+        Ok(n)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        self.inner.len()
+    }
+
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.inner.truncate(len)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.inner.sync()
+    }
+
+    fn start_compaction(&self) -> Result<Self> {
+        Ok(Self::new(self.inner.start_compaction()?))
+    }
+
+    fn finish_compaction(&mut self, compacted: Self) -> Result<()> {
+        // faults were aimed at the old layout, so they do not carry over
+        self.inner.finish_compaction(compacted.inner)?;
+        self.short_write = None;
+        self.bit_flips.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod storage_test {
+    use super::*;
+
+    #[test]
+    fn reader_seeks_and_reads() {
+        let mut storage = MemStorage::new();
+        assert_eq!(storage.append(b"abc").unwrap(), 0);
+        assert_eq!(storage.append(b"def").unwrap(), 3);
+
+        let mut reader = StorageReader::new(&storage);
+        reader.seek(SeekFrom::End(-4)).unwrap();
+        let mut buf = String::new();
+        reader.read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "cdef");
+    }
+
+    #[test]
+    fn file_compaction_replaces_file() {
+        let path = std::env::temp_dir().join(format!("akv-{}-storage", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut storage = FileStorage::open(&path).unwrap();
+        storage.append(b"old").unwrap();
+        let mut compacted = storage.start_compaction().unwrap();
+        compacted.append(b"new").unwrap();
+        storage.finish_compaction(compacted).unwrap();
+
+        assert_eq!(storage.path(), path);
+        assert_eq!(storage.len().unwrap(), 3);
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        assert!(matches!(
+            FileStorage::open(&path),
+            Err(ActionKvError::Locked)
+        ));
+
+        drop(storage);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn faults() {
+        let mut storage = FaultyStorage::new(MemStorage::new());
+        storage.append(b"abc").unwrap();
+        storage.short_write_next(1);
+        assert!(storage.append(b"def").is_err());
+        storage.flip_bit(1, 0);
+
+        let mut buf = [0; 8];
+        let n = storage.read_at(0, &mut buf).unwrap();
+        assert_eq!(&buf[..n], b"acc");
+    }
+}
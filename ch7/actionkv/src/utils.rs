@@ -1,26 +1,74 @@
-use std::collections::HashMap;
-use std::io::{self, Write};
-use std::io::{Error, ErrorKind};
-use std::path::PathBuf;
+use std::borrow::Cow;
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::str::FromStr;
 
-use crate::ActionKV;
-use crate::ByteString;
+use crate::bloom::BloomFilter;
+use crate::index::{Index, IndexMode};
+use crate::merge::BuiltinMerge;
+use crate::{
+    is_internal_key, ActionKV, ActionKvError, Limits, Result, StoreStats, BLOOM_KEY, INDEX_KEY,
+};
 use clap::{Command, FromArgMatches, Parser, Subcommand};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::{Context, Editor, Helper, Highlighter, Hinter};
+use tracing::{error, info, warn};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-type Cache = HashMap<ByteString, u64>;
+// sizing of the bloom filter when enabled with --bloom, it grows with the store
+const BLOOM_CAPACITY: usize = 10_000;
+const BLOOM_FP_RATE: f64 = 0.01;
 
-const INDEX_KEY: &str = "+index+";
+// how deep scripts may source other scripts, so a loop fails instead of overflowing
+const MAX_SOURCE_DEPTH: usize = 16;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(after_help = "Exit codes: 2 key not found, 3 data corruption, \
+4 key or value too large, 5 file locked, 6 I/O error, 7 no merge operator")]
 pub struct Cli {
     /// FILE for ActionKV
     #[arg(value_name = "FILE")]
     fname: PathBuf,
 
+    /// Answer lookups for missing keys with a bloom filter
+    #[arg(long)]
+    bloom: bool,
+
+    /// How keys are kept in memory, hashed keeps only a hash and an offset per key
+    #[arg(long, value_enum, default_value_t = IndexMode::Full)]
+    index: IndexMode,
+
+    /// Keep versions from the last N sequence numbers when compacting
+    #[arg(long, value_name = "N")]
+    retention: Option<u64>,
+
+    /// Largest key accepted, in bytes
+    #[arg(long, value_name = "BYTES", default_value_t = Limits::default().max_key_len)]
+    max_key_size: u64,
+
+    /// Largest value accepted, in bytes, values over 4 GiB need a larger limit
+    #[arg(long, value_name = "BYTES", default_value_t = Limits::default().max_value_len)]
+    max_value_size: u64,
+
+    /// Operator that folds the operands written by the merge command
+    #[arg(long, value_enum, value_name = "OPERATOR")]
+    merge_operator: Option<BuiltinMerge>,
+
+    /// Run the commands in SCRIPT instead of starting the prompt
+    #[arg(long, value_name = "SCRIPT")]
+    script: Option<PathBuf>,
+
     /// Operation commands
     #[command(subcommand)]
-    command: Option<Subcommands>,
+    command: Option<Line>,
 }
 
 #[derive(Subcommand, Debug)]
@@ -33,151 +81,665 @@ enum Subcommands {
     Delete { key: String },
     /// Replaces an old value with a new one
     Update { key: String, value: String },
+    /// Folds operand into the value at key with the merge operator
+    Merge { key: String, operand: String },
     /// Retrieves the value as UTF8 String at key from the store
     Show { key: String },
+    /// Reports how the bloom filter answered lookups
+    BloomStats,
+    /// Lists the past values of key with their sequence numbers and offsets
+    History { key: String },
+    /// Rewrites the file without values that were replaced or deleted
+    Compact,
+    /// Reports how much of the file is live and how large keys and values are
+    Stats {
+        /// How many of the largest values to list
+        #[arg(long, value_name = "N", default_value_t = 5)]
+        top: usize,
+    },
+    /// Lists live keys with the offset and size of their records
+    Keys {
+        #[arg(long)]
+        prefix: Option<String>,
+    },
+}
+
+/// What a command line asks for, scripts are run by the session
+/// rather than against the store
+#[derive(Subcommand, Debug)]
+enum Line {
+    #[command(flatten)]
+    Store(Subcommands),
+    /// Runs the commands in file, one per line, stopping at the first failure
+    Source { file: PathBuf },
 }
 
 impl Subcommands {
-    fn execute(&self, store: &mut ActionKV, disk_index: bool) {
+    fn execute(&self, store: &mut ActionKV, disk_index: bool) -> Result<()> {
         let mut modified = true;
         if disk_index {
-            if let Err(err) = read_index_from_disk(store) {
-                eprintln!("{err}");
-            }
+            read_index_from_disk(store)?;
         }
-        match self {
+        let result = match self {
             Subcommands::Get { key } | Subcommands::Show { key } => {
                 modified = false;
-                match store.get(key.as_bytes()) {
-                    Ok(value) => match value {
-                        None => println!("None"),
-                        Some(value) => {
-                            if let Subcommands::Get { .. } = self {
-                                println!("{:?}: {:?}", key.as_bytes(), value)
-                            } else {
-                                println!("{key:?}: {:?}", String::from_utf8_lossy(&value))
-                            }
+                // a missing key is an answer, not a failure
+                match store.get(key.as_bytes())? {
+                    None => println!("None"),
+                    Some(value) => {
+                        if let Subcommands::Get { .. } = self {
+                            println!("{:?}: {:?}", key.as_bytes(), value)
+                        } else {
+                            println!("{key:?}: {:?}", String::from_utf8_lossy(&value))
                         }
-                    },
-                    Err(err) => eprintln!("{err:?}"),
+                    }
                 }
+                Ok(())
             }
-            Subcommands::Insert { key, value } => {
-                match store.insert(key.as_bytes(), value.as_bytes()) {
-                    Ok(_) => println!("Insert {key:?} {value:?}"),
-                    Err(err) => eprintln!("{err}"),
+            Subcommands::Insert { key, value } => store
+                .insert(key.as_bytes(), value.as_bytes())
+                .map(|_| println!("Insert {key:?} {value:?}")),
+            Subcommands::Delete { key } => store
+                .delete(key.as_bytes())
+                .map(|_| println!("Delete {key:?}")),
+            Subcommands::Update { key, value } => store
+                .update(key.as_bytes(), value.as_bytes())
+                .map(|_| println!("Update {key:?} {value:?}")),
+            Subcommands::Merge { key, operand } => store
+                .merge(key.as_bytes(), operand.as_bytes())
+                .map(|_| println!("Merge {key:?} {operand:?}")),
+            Subcommands::BloomStats => {
+                modified = false;
+                match store.bloom_filter() {
+                    None => println!("bloom filter is not enabled"),
+                    Some(bloom) => {
+                        let stats = store.bloom_stats();
+                        println!(
+                            "capacity: {}, target fp rate: {}, size: {} bytes",
+                            bloom.capacity(),
+                            bloom.fp_rate(),
+                            bloom.size_in_bytes()
+                        );
+                        println!(
+                            "lookups: {}, rejected: {}, false positives: {}, fp rate: {:.4}",
+                            stats.lookups(),
+                            stats.negatives(),
+                            stats.false_positives(),
+                            stats.false_positive_rate()
+                        );
+                    }
                 }
+                Ok(())
             }
-            Subcommands::Delete { key } => match store.delete(key.as_bytes()) {
-                Ok(_) => println!("Delete {key:?}"),
-                Err(err) => eprintln!("{err}"),
-            },
-            Subcommands::Update { key, value } => {
-                match store.update(key.as_bytes(), value.as_bytes()) {
-                    Ok(_) => println!("Update {key:?} {value:?}"),
-                    Err(err) => eprintln!("{err}"),
+            Subcommands::History { key } => {
+                modified = false;
+                for version in store.history(key.as_bytes())? {
+                    let seq = match version.seq {
+                        Some(seq) => seq.to_string(),
+                        None => "-".to_string(),
+                    };
+                    match version.value {
+                        Some(value) => println!(
+                            "{seq}\t@{}\t{:?}",
+                            version.position,
+                            String::from_utf8_lossy(&value)
+                        ),
+                        None => println!("{seq}\t@{}\t<deleted>", version.position),
+                    }
                 }
+                Ok(())
             }
-        }
-        if disk_index && modified {
-            if let Err(err) = write_index_to_disk(store) {
-                eprintln!("{err}");
-            } else {
-                eprintln!("Write index to disk");
+            Subcommands::Stats { top } => {
+                modified = false;
+                store.stats(*top).map(|stats| print_stats(store, &stats))
             }
+            Subcommands::Keys { prefix } => {
+                modified = false;
+                let prefix = prefix.as_deref().unwrap_or_default();
+                store.keys(prefix.as_bytes()).map(|entries| {
+                    for entry in entries {
+                        println!(
+                            "{:?}\t@{}\t{} bytes",
+                            String::from_utf8_lossy(&entry.key),
+                            entry.position,
+                            entry.len
+                        );
+                    }
+                })
+            }
+            Subcommands::Compact => store
+                .compact()
+                .map(|_| println!("Compact {:?}", store.storage().path())),
+        };
+        if disk_index && modified {
+            write_index_to_disk(store)?;
+            info!("write index to disk");
         }
+        result
     }
 }
 
-fn read_index_from_disk(store: &mut ActionKV) -> Result<(), std::io::Error> {
-    match store.get(INDEX_KEY.as_bytes()) {
-        Ok(value) => {
-            if let Some(index_as_bytes) = value {
-                match bincode::deserialize::<Cache>(&index_as_bytes) {
-                    Ok(index) => {
-                        store.index = index;
-                        // after first time read from disk,
-                        // next time need to insert INDEX_KEY back to the index,
-                        // because the index from disk does not contain the INDEX_KEY
-                        // could append the index from disk to the index with more time complexity
-                        store.insert(INDEX_KEY.as_bytes(), &index_as_bytes)
-                    }
-                    Err(err) => Err(Error::new(ErrorKind::Other, err)),
-                }
-            } else {
-                Err(Error::new(ErrorKind::Other, "index is not on the memory"))
-            }
+fn print_stats(store: &ActionKV, stats: &StoreStats) {
+    let dead_share = match stats.file_bytes {
+        0 => 0.0,
+        total => stats.dead_bytes as f64 * 100.0 / total as f64,
+    };
+    println!(
+        "records: {}, tombstones: {}, live keys: {}",
+        stats.records, stats.tombstones, stats.live_keys
+    );
+    println!(
+        "file: {} bytes, dead: {} bytes ({dead_share:.1}%)",
+        stats.file_bytes, stats.dead_bytes
+    );
+    println!(
+        "average key: {:.1} bytes, average value: {:.1} bytes",
+        stats.avg_key_len, stats.avg_value_len
+    );
+    println!(
+        "index: {:?}, about {} bytes in memory",
+        store.index_mode(),
+        stats.index_bytes
+    );
+    if !stats.largest_values.is_empty() {
+        println!("largest values:");
+        for (key, len) in &stats.largest_values {
+            println!("  {len}\t{:?}", String::from_utf8_lossy(key));
+        }
+    }
+}
+
+fn read_index_from_disk(store: &mut ActionKV) -> Result<()> {
+    if store.bloom.is_some() {
+        read_bloom_from_disk(store)?;
+    }
+    let Some(index_as_bytes) = store.get(INDEX_KEY.as_bytes())? else {
+        return Err(ActionKvError::NotFound(INDEX_KEY.as_bytes().to_vec()));
+    };
+    let index = deserialize_record::<Index>(store, INDEX_KEY, &index_as_bytes)?;
+    let bloom_position = store.index_get(BLOOM_KEY.as_bytes())?;
+    store.index = index;
+    // keep the filter record reachable without appending it again
+    if let Some(position) = bloom_position {
+        store.index_insert(BLOOM_KEY.as_bytes(), position)?;
+    }
+    write_outgrown_bloom(store)?;
+    // after first time read from disk,
+    // next time need to insert INDEX_KEY back to the index,
+    // because the index from disk does not contain the INDEX_KEY
+    // could append the index from disk to the index with more time complexity
+    store.insert(INDEX_KEY.as_bytes(), &index_as_bytes)
+}
+
+/// The filter is written right before the index, so it always matches
+/// the keys of the index it was written with.
+fn read_bloom_from_disk(store: &mut ActionKV) -> Result<()> {
+    let Some(bloom_as_bytes) = store.get(BLOOM_KEY.as_bytes())? else {
+        return Err(ActionKvError::NotFound(BLOOM_KEY.as_bytes().to_vec()));
+    };
+    let bloom = deserialize_record::<BloomFilter>(store, BLOOM_KEY, &bloom_as_bytes)?;
+    store.bloom = Some(bloom);
+    Ok(())
+}
+
+/// A filter written for a smaller store passes nearly every missing key,
+/// so resize it and write it again
+fn write_outgrown_bloom(store: &mut ActionKV) -> Result<()> {
+    let capacity = store.bloom.as_ref().map(BloomFilter::capacity);
+    store.fit_bloom_filter()?;
+    match &store.bloom {
+        Some(bloom) if Some(bloom.capacity()) != capacity => {
+            info!("bloom filter resized to {} keys", bloom.capacity());
+            let bloom_as_bytes = bincode::serialize(bloom).map_err(io::Error::other)?;
+            store.insert(BLOOM_KEY.as_bytes(), &bloom_as_bytes)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// A value that passed its checksum but does not decode is still corrupt
+fn deserialize_record<T: serde::de::DeserializeOwned>(
+    store: &ActionKV,
+    key: &str,
+    bytes: &[u8],
+) -> Result<T> {
+    match bincode::deserialize::<T>(bytes) {
+        Ok(value) => Ok(value),
+        Err(err) => {
+            error!("unable to decode {key}: {err}");
+            let offset = store.index_get(key.as_bytes())?.unwrap_or_default();
+            Err(ActionKvError::Corruption { offset })
         }
-        Err(err) => Err(err),
     }
 }
 
 /// Write index to disk, but index does not contain the index
 /// if using akv_mem to add key-value, but will not update the index from the disk
 /// so next time using akv_disk will not get the updated value.
-fn write_index_to_disk(store: &mut ActionKV) -> Result<(), std::io::Error> {
+fn write_index_to_disk(store: &mut ActionKV) -> Result<()> {
     // remove index's index from index first to avoid recursion
-    store.index.remove(INDEX_KEY.as_bytes());
-    match bincode::serialize(&store.index) {
-        Ok(index_as_bytes) => {
-            // clear current index first
-            store.index.clear();
-            match store.insert(INDEX_KEY.as_bytes(), &index_as_bytes) {
-                Ok(_) => Ok(()),
-                Err(err) => Err(err),
-            }
+    store.index_remove(INDEX_KEY.as_bytes())?;
+    store.index_remove(BLOOM_KEY.as_bytes())?;
+    let bloom_as_bytes = match &mut store.bloom {
+        Some(bloom) => {
+            // the filter read back from disk has to let its own lookups through
+            bloom.insert(BLOOM_KEY.as_bytes());
+            bloom.insert(INDEX_KEY.as_bytes());
+            Some(bincode::serialize(bloom).map_err(io::Error::other)?)
         }
-        Err(err) => Err(Error::new(ErrorKind::Other, err)),
+        None => None,
+    };
+    let index_as_bytes = bincode::serialize(&store.index).map_err(io::Error::other)?;
+    // clear current index first
+    store.index.clear();
+    if let Some(bloom_as_bytes) = bloom_as_bytes {
+        store.insert(BLOOM_KEY.as_bytes(), &bloom_as_bytes)?;
     }
+    store.insert(INDEX_KEY.as_bytes(), &index_as_bytes)
 }
 
-pub fn run(disk_index: bool) {
-    let args = Cli::parse();
+/// Log to stderr, filtered by RUST_LOG, warnings and errors by default
+pub fn init_tracing() {
+    let filter_layer =
+        Targets::from_str(std::env::var("RUST_LOG").as_deref().unwrap_or("warn")).unwrap();
+    let format_layer = tracing_subscriber::fmt::layer().with_writer(io::stderr);
+    tracing_subscriber::registry()
+        .with(filter_layer)
+        .with(format_layer)
+        .init();
+}
 
-    let path = args.fname;
-    let mut store = ActionKV::open(&path).expect("unable to open file");
-    store.load().expect("unable to load data");
+fn open_store(args: &Cli, disk_index: bool) -> Result<ActionKV> {
+    let mut store = ActionKV::open(&args.fname)?;
+    store.set_index_mode(args.index);
+    store.set_retention(args.retention);
+    store.set_limits(Limits {
+        max_key_len: args.max_key_size,
+        max_value_len: args.max_value_size,
+    });
+    if let Some(operator) = args.merge_operator {
+        store.set_merge_operator(operator.operator());
+    }
+    if args.bloom {
+        store.enable_bloom_filter(BLOOM_CAPACITY, BLOOM_FP_RATE);
+    }
+    store.load()?;
 
     // when using akv_disk first thing to do is update the disk index
     // because some change may not write to the disk
     if disk_index {
-        if let Err(err) = write_index_to_disk(&mut store) {
-            eprintln!("{err}");
+        write_index_to_disk(&mut store)?;
+        info!("updating newest index to the disk");
+    }
+    Ok(store)
+}
+
+/// Where the prompt keeps its history between sessions
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".actionkv_history"))
+}
+
+/// Completes subcommand names as the first word and known keys after it.
+/// A line ending in `\` continues on the next one.
+#[derive(Helper, Hinter, Highlighter)]
+struct PromptHelper {
+    commands: Vec<String>,
+    keys: Vec<String>,
+}
+
+impl PromptHelper {
+    fn new(prompt: &Command) -> Self {
+        let mut commands: Vec<String> = prompt
+            .get_subcommands()
+            .map(|command| command.get_name().to_string())
+            .chain(["exit", "quit"].map(String::from))
+            .collect();
+        commands.sort();
+        Self {
+            commands,
+            keys: vec![],
+        }
+    }
+
+    /// Only keys kept by a full index are offered, a hashed one has none in memory
+    fn refresh_keys(&mut self, store: &ActionKV) {
+        self.keys = store
+            .index
+            .keys()
+            .into_iter()
+            .flatten()
+            .filter(|key| !is_internal_key(key))
+            .filter_map(|key| String::from_utf8(key.to_vec()).ok())
+            .collect();
+        self.keys.sort();
+    }
+}
+
+impl Completer for PromptHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let line = &line[..pos];
+        let start = line.rfind(char::is_whitespace).map_or(0, |i| i + 1);
+        let (before, word) = line.split_at(start);
+        let candidates = match before.split_whitespace().collect::<Vec<_>>()[..] {
+            [] => &self.commands,
+            [command] if command != "source" => &self.keys,
+            _ => return Ok((start, vec![])),
+        };
+        let matches = candidates
+            .iter()
+            .filter(|candidate| candidate.starts_with(word))
+            .map(|candidate| {
+                shlex::try_quote(candidate).map_or_else(|_| candidate.clone(), Cow::into_owned)
+            })
+            .collect();
+        Ok((start, matches))
+    }
+}
+
+impl Validator for PromptHelper {
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        if ctx.input().ends_with('\\') {
+            Ok(ValidationResult::Incomplete)
         } else {
-            eprintln!("Updating newest index to the disk")
+            Ok(ValidationResult::Valid(None))
+        }
+    }
+}
+
+/// What the prompt does after a line ran
+enum Flow {
+    Continue,
+    Exit,
+}
+
+/// A line that did not parse, or a command that failed
+#[derive(Debug)]
+enum LineError {
+    Usage(String),
+    Store(ActionKvError),
+}
+
+impl LineError {
+    fn exit_code(&self) -> u8 {
+        match self {
+            LineError::Usage(_) => 1,
+            LineError::Store(err) => err.exit_code(),
+        }
+    }
+}
+
+impl Display for LineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LineError::Usage(msg) => write!(f, "{}", msg.trim_end()),
+            LineError::Store(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl From<ActionKvError> for LineError {
+    fn from(value: ActionKvError) -> Self {
+        Self::Store(value)
+    }
+}
+
+/// Runs commands against an open store, from the prompt or from scripts
+struct Session {
+    store: ActionKV,
+    disk_index: bool,
+    prompt: Command,
+    depth: usize,
+}
+
+impl Session {
+    fn new(store: ActionKV, disk_index: bool) -> Self {
+        let prompt = Command::new("Interactive prompt").no_binary_name(true);
+        let mut prompt = Line::augment_subcommands(prompt);
+        // adds the help subcommand, so completion offers it too
+        prompt.build();
+        Self {
+            store,
+            disk_index,
+            prompt,
+            depth: 0,
         }
     }
 
-    match &args.command {
-        Some(command) => command.execute(&mut store, disk_index),
-        None => {
-            let prompt = Command::new("Interactive prompt").no_binary_name(true);
-            let mut prompt = Subcommands::augment_subcommands(prompt);
-            loop {
-                print!("[{:?}]> ", path.file_name().expect("unable to open file"));
-                io::stdout().flush().expect("unable to flush stdout");
+    fn run(&mut self, line: &Line) -> std::result::Result<(), LineError> {
+        match line {
+            Line::Store(command) => Ok(command.execute(&mut self.store, self.disk_index)?),
+            Line::Source { file } => self.source(file),
+        }
+    }
 
-                let mut buffer = String::new();
-                io::stdin()
-                    .read_line(&mut buffer)
-                    .expect("unable to readline from stdin");
+    /// Blank lines and `#` comments do nothing
+    fn run_line(&mut self, line: &str) -> std::result::Result<Flow, LineError> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(Flow::Continue);
+        }
+        if line == "exit" || line == "quit" {
+            return Ok(Flow::Exit);
+        }
+        let Some(raw_args) = shlex::split(line) else {
+            return Err(LineError::Usage(
+                "Input is not valid shell words".to_string(),
+            ));
+        };
+        let matches = match self.prompt.try_get_matches_from_mut(raw_args) {
+            Ok(matches) => matches,
+            // help and version requests are not failures
+            Err(err) if !err.use_stderr() => {
+                let _ = err.print();
+                return Ok(Flow::Continue);
+            }
+            Err(err) => return Err(LineError::Usage(err.to_string())),
+        };
+        let line =
+            Line::from_arg_matches(&matches).map_err(|err| LineError::Usage(err.to_string()))?;
+        self.run(&line)?;
+        Ok(Flow::Continue)
+    }
 
-                if buffer.trim() == "exit" || buffer.trim() == "quit" {
-                    return;
+    /// Lines ending in `\` continue on the next one, `exit` ends the script early
+    fn source(&mut self, path: &Path) -> std::result::Result<(), LineError> {
+        if self.depth == MAX_SOURCE_DEPTH {
+            return Err(LineError::Usage(format!(
+                "scripts nested more than {MAX_SOURCE_DEPTH} deep"
+            )));
+        }
+        let script = fs::read_to_string(path).map_err(ActionKvError::from)?;
+
+        self.depth += 1;
+        let result = self.run_script(path, &script);
+        self.depth -= 1;
+        result
+    }
+
+    fn run_script(&mut self, path: &Path, script: &str) -> std::result::Result<(), LineError> {
+        let mut pending = String::new();
+        let mut first_line = 1;
+        for (n, line) in script.lines().enumerate() {
+            if pending.is_empty() {
+                first_line = n + 1;
+            }
+            if let Some(head) = line.strip_suffix('\\') {
+                pending.push_str(head);
+                pending.push(' ');
+                continue;
+            }
+            pending.push_str(line);
+            let line = std::mem::take(&mut pending);
+            match self.run_line(&line) {
+                Ok(Flow::Continue) => {}
+                Ok(Flow::Exit) => return Ok(()),
+                Err(err) => {
+                    error!("stopped {:?} at line {first_line}", path);
+                    return Err(err);
                 }
+            }
+        }
+        // a continuation on the last line
+        self.run_line(&pending).map(|_| ())
+    }
 
-                if let Some(raw_args) = shlex::split(&buffer) {
-                    match prompt.try_get_matches_from_mut(raw_args.into_iter()) {
-                        Ok(matches) => match Subcommands::from_arg_matches(&matches) {
-                            Ok(command) => command.execute(&mut store, disk_index),
-                            Err(err) => eprintln!("{err}"),
-                        },
-                        Err(err) => eprintln!("{err}"),
+    fn interactive(&mut self, path: &Path) -> ExitCode {
+        let mut editor = match Editor::<PromptHelper, DefaultHistory>::new() {
+            Ok(editor) => editor,
+            Err(err) => {
+                error!("unable to start the prompt: {err}");
+                return ExitCode::FAILURE;
+            }
+        };
+        let mut helper = PromptHelper::new(&self.prompt);
+        helper.refresh_keys(&self.store);
+        editor.set_helper(Some(helper));
+
+        let history = history_path();
+        if let Some(history) = &history {
+            // there is none before the first session
+            let _ = editor.load_history(history);
+        }
+
+        let prompt = format!("[{:?}]> ", path.file_name().expect("unable to open file"));
+        loop {
+            match editor.readline(&prompt) {
+                Ok(line) => {
+                    if !line.trim().is_empty() {
+                        let _ = editor.add_history_entry(line.as_str());
+                    }
+                    match self.run_line(&line.replace("\\\n", " ")) {
+                        Ok(Flow::Continue) => {}
+                        Ok(Flow::Exit) => break,
+                        // shown like help, not logged, a typo is no failure of the store
+                        Err(err @ LineError::Usage(_)) => println!("{err}"),
+                        Err(err) => error!("{err}"),
                     }
-                } else {
-                    eprintln!("Input is not valid shell words")
+                    if let Some(helper) = editor.helper_mut() {
+                        helper.refresh_keys(&self.store);
+                    }
+                }
+                // ctrl-c drops the line, ctrl-d leaves
+                Err(ReadlineError::Interrupted) => {}
+                Err(ReadlineError::Eof) => break,
+                Err(err) => {
+                    error!("unable to read from the prompt: {err}");
+                    return ExitCode::FAILURE;
                 }
             }
         }
+
+        if let Some(history) = &history {
+            if let Err(err) = editor.save_history(history) {
+                warn!("unable to save history to {:?}: {err}", history);
+            }
+        }
+        ExitCode::SUCCESS
+    }
+}
+
+/// Runs the CLI and reports failures through the exit code, see `ActionKvError::exit_code`
+pub fn run(disk_index: bool) -> ExitCode {
+    let args = Cli::parse();
+    init_tracing();
+
+    let store = match open_store(&args, disk_index) {
+        Ok(store) => store,
+        Err(err) => {
+            error!("unable to open {:?}: {err}", args.fname);
+            return ExitCode::from(err.exit_code());
+        }
+    };
+
+    let mut session = Session::new(store, disk_index);
+    let result = match (&args.command, &args.script) {
+        (Some(command), _) => session.run(command),
+        (None, Some(script)) => session.source(script),
+        (None, None) => return session.interactive(&args.fname),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            error!("{err}");
+            ExitCode::from(err.exit_code())
+        }
+    }
+}
+
+#[cfg(test)]
+mod utils_test {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("akv-{}-{name}", std::process::id()))
+    }
+
+    #[test]
+    fn completes_commands_then_keys() {
+        let path = temp_path("complete");
+        let _ = fs::remove_file(&path);
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"apple", b"1").unwrap();
+        store.insert(b"apple pie", b"2").unwrap();
+        store.insert(b"banana", b"3").unwrap();
+        let session = Session::new(store, false);
+
+        let mut helper = PromptHelper::new(&session.prompt);
+        helper.refresh_keys(&session.store);
+        let history = DefaultHistory::new();
+        let ctx = Context::new(&history);
+
+        let (start, found) = helper.complete("ins", 3, &ctx).unwrap();
+        assert_eq!((start, found), (0, vec!["insert".to_string()]));
+        let (start, found) = helper.complete("get ap", 6, &ctx).unwrap();
+        assert_eq!(start, 4);
+        assert_eq!(found, vec!["apple", "'apple pie'"]);
+        assert!(helper
+            .complete("get apple x", 11, &ctx)
+            .unwrap()
+            .1
+            .is_empty());
+
+        drop(session);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn source_runs_until_a_failure() {
+        let path = temp_path("source-store");
+        let script = temp_path("source-script");
+        let _ = fs::remove_file(&path);
+        fs::write(
+            &script,
+            "# setup\ninsert a 1\n\ninsert b \\\n  2\ndelete missing\ninsert c 3\n",
+        )
+        .unwrap();
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        let mut session = Session::new(store, false);
+        let err = session.source(&script).unwrap_err();
+        assert_eq!(err.exit_code(), 2);
+        assert_eq!(session.store.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(session.store.get(b"c").unwrap(), None);
+
+        // looking up a missing key does not stop the script
+        fs::write(&script, "get missing\nshow missing\ninsert c 3\n").unwrap();
+        session.source(&script).unwrap();
+        assert_eq!(session.store.get(b"c").unwrap(), Some(b"3".to_vec()));
+
+        fs::write(&script, "source ".to_string() + script.to_str().unwrap()).unwrap();
+        assert_eq!(session.source(&script).unwrap_err().exit_code(), 1);
+
+        drop(session);
+        fs::remove_file(path).unwrap();
+        fs::remove_file(script).unwrap();
     }
 }
@@ -0,0 +1,346 @@
+use std::{collections::HashMap, error::Error, fmt::Display, path::Path};
+
+use aes::Aes128;
+use cmac::{Cmac, KeyInit, Mac};
+use md5::{Digest, Md5};
+use sha1::Sha1;
+
+use crate::packet::{NtpError, NTP_MESSAGE_LENGTH};
+
+/// Longest key ID and MAC that can follow the header
+pub const MAX_MAC_LENGTH: usize = 4 + 20;
+
+/// Key ID of zero with no MAC, sent back when a request fails authentication
+// see: https://datatracker.ietf.org/doc/html/rfc5905#section-7.4
+const CRYPTO_NAK_LENGTH: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MacAlgorithm {
+    Md5,
+    Sha1,
+    // see: https://datatracker.ietf.org/doc/html/rfc8573
+    AesCmac,
+}
+
+impl MacAlgorithm {
+    /// Accepts the names of both ntpd and chrony keys files
+    fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_uppercase().as_str() {
+            "M" | "MD5" => Some(Self::Md5),
+            "SHA1" | "SHA-1" => Some(Self::Sha1),
+            "AES128CMAC" | "AES128" | "CMAC" => Some(Self::AesCmac),
+            _ => None,
+        }
+    }
+
+    fn mac_len(self) -> usize {
+        match self {
+            MacAlgorithm::Md5 | MacAlgorithm::AesCmac => 16,
+            MacAlgorithm::Sha1 => 20,
+        }
+    }
+}
+
+/// A symmetric key shared by a client and a server
+#[derive(Clone, PartialEq, Eq)]
+pub struct Key {
+    pub id: u32,
+    pub algorithm: MacAlgorithm,
+    secret: Vec<u8>,
+}
+
+// keep secrets out of logs
+impl std::fmt::Debug for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Key")
+            .field("id", &self.id)
+            .field("algorithm", &self.algorithm)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Key {
+    pub fn new(id: u32, algorithm: MacAlgorithm, secret: Vec<u8>) -> Result<Self, String> {
+        if id == 0 {
+            return Err("key ID 0 is reserved for crypto-NAKs".to_string());
+        }
+        if algorithm == MacAlgorithm::AesCmac && secret.len() != 16 {
+            return Err(format!("AES-CMAC needs 16 key bytes, not {}", secret.len()));
+        }
+        Ok(Self {
+            id,
+            algorithm,
+            secret,
+        })
+    }
+
+    fn mac(&self, data: &[u8]) -> Vec<u8> {
+        match self.algorithm {
+            MacAlgorithm::Md5 => Md5::new_with_prefix(&self.secret)
+                .chain_update(data)
+                .finalize()
+                .to_vec(),
+            MacAlgorithm::Sha1 => Sha1::new_with_prefix(&self.secret)
+                .chain_update(data)
+                .finalize()
+                .to_vec(),
+            MacAlgorithm::AesCmac => {
+                let mut mac = Cmac::<Aes128>::new_from_slice(&self.secret)
+                    .expect("key length is checked by Key::new");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+        }
+    }
+
+    /// `packet` followed by the key ID and its MAC
+    pub fn sign(&self, packet: &[u8]) -> Vec<u8> {
+        let mut signed = packet.to_vec();
+        signed.extend_from_slice(&self.id.to_be_bytes());
+        signed.extend_from_slice(&self.mac(packet));
+        signed
+    }
+
+    /// Compare in constant time, so the time taken tells nothing about the MAC
+    fn verify(&self, packet: &[u8], mac: &[u8]) -> bool {
+        mac.len() == self.algorithm.mac_len()
+            && self
+                .mac(packet)
+                .iter()
+                .zip(mac)
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+
+    /// Check that `data` carries a MAC made with this key
+    pub fn verify_packet(&self, data: &[u8]) -> Result<(), NtpError> {
+        match Authenticator::read(data)? {
+            Authenticator::Mac { key_id, mac }
+                if key_id == self.id && self.verify(&data[..NTP_MESSAGE_LENGTH], mac) =>
+            {
+                Ok(())
+            }
+            Authenticator::CryptoNak => Err(NtpError::CryptoNak),
+            _ => Err(NtpError::BadMac),
+        }
+    }
+}
+
+/// What follows the header of a packet
+#[derive(Debug, PartialEq, Eq)]
+pub enum Authenticator<'a> {
+    None,
+    CryptoNak,
+    Mac { key_id: u32, mac: &'a [u8] },
+}
+
+impl<'a> Authenticator<'a> {
+    /// Extension fields are not supported, so anything past the header
+    /// has to be a crypto-NAK or a key ID with an MD5, SHA1 or CMAC MAC
+    pub fn read(data: &'a [u8]) -> Result<Self, NtpError> {
+        let Some(trailer) = data.get(NTP_MESSAGE_LENGTH..) else {
+            return Err(NtpError::Truncated(data.len()));
+        };
+        let key_id = |trailer: &[u8]| u32::from_be_bytes(trailer[..4].try_into().unwrap());
+        match trailer.len() {
+            0 => Ok(Authenticator::None),
+            CRYPTO_NAK_LENGTH if key_id(trailer) == 0 => Ok(Authenticator::CryptoNak),
+            20 | 24 => Ok(Authenticator::Mac {
+                key_id: key_id(trailer),
+                mac: &trailer[4..],
+            }),
+            _ => Err(NtpError::BadMac),
+        }
+    }
+
+    /// Reply sent to requests whose MAC does not check out
+    pub fn crypto_nak(packet: &[u8]) -> Vec<u8> {
+        let mut nak = packet.to_vec();
+        nak.extend_from_slice(&[0; CRYPTO_NAK_LENGTH]);
+        nak
+    }
+}
+
+#[derive(Debug)]
+pub enum KeysError {
+    Io(std::io::Error),
+    Invalid { line: usize, reason: String },
+}
+
+impl Display for KeysError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeysError::Io(err) => write!(f, "{err}"),
+            KeysError::Invalid { line, reason } => write!(f, "line {line}: {reason}"),
+        }
+    }
+}
+
+impl Error for KeysError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            KeysError::Io(err) => Some(err),
+            KeysError::Invalid { .. } => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for KeysError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+/// Keys by ID, read from a keys file with one `ID TYPE KEY` per line like
+///
+/// ```text
+/// # ntpd style, hex when longer than 20 characters
+/// 1 MD5 secret
+/// 2 SHA1 3f1d5e0b1c6a9e2f4b7d8c0a1e3f5b7d9c2e4a6b
+/// # chrony style prefixes
+/// 3 AES128 HEX:2b7e151628aed2a6abf7158809cf4f3c
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct Keys(HashMap<u32, Key>);
+
+fn decode_secret(text: &str) -> Result<Vec<u8>, String> {
+    fn hex(text: &str) -> Result<Vec<u8>, String> {
+        if !text.len().is_multiple_of(2) || !text.is_ascii() {
+            return Err("expected an even number of hex digits".to_string());
+        }
+        (0..text.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&text[i..i + 2], 16))
+            .collect::<Result<_, _>>()
+            .map_err(|err| format!("invalid hex key: {err}"))
+    }
+
+    if let Some(text) = text.strip_prefix("HEX:") {
+        hex(text)
+    } else if let Some(text) = text.strip_prefix("ASCII:") {
+        Ok(text.as_bytes().to_vec())
+    } else if text.len() > 20 {
+        hex(text)
+    } else {
+        Ok(text.as_bytes().to_vec())
+    }
+}
+
+impl Keys {
+    pub fn load(path: &Path) -> Result<Self, KeysError> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, KeysError> {
+        let mut keys = HashMap::new();
+        for (i, line) in text.lines().enumerate() {
+            let invalid = |reason: String| KeysError::Invalid {
+                line: i + 1,
+                reason,
+            };
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [id, algorithm, secret] = fields[..] else {
+                return Err(invalid("expected ID TYPE KEY".to_string()));
+            };
+            let id = id
+                .parse()
+                .map_err(|_| invalid(format!("invalid key ID {id:?}")))?;
+            let algorithm = MacAlgorithm::parse(algorithm)
+                .ok_or_else(|| invalid(format!("unsupported key type {algorithm:?}")))?;
+            let key = Key::new(id, algorithm, decode_secret(secret).map_err(&invalid)?)
+                .map_err(&invalid)?;
+            keys.insert(id, key);
+        }
+        Ok(Self(keys))
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Key> {
+        self.0.get(&id)
+    }
+}
+
+#[cfg(test)]
+mod auth_test {
+    use super::*;
+
+    const KEYS: &str = "
+        # comment
+        1 MD5 secret
+        2 sha1 ASCII:another   # trailing comment
+        3 AES128 HEX:2b7e151628aed2a6abf7158809cf4f3c
+    ";
+
+    #[test]
+    fn parse_keys_file() {
+        let keys = Keys::parse(KEYS).unwrap();
+        assert_eq!(keys.get(1).unwrap().algorithm, MacAlgorithm::Md5);
+        assert_eq!(keys.get(2).unwrap().secret, b"another");
+        assert_eq!(keys.get(3).unwrap().secret.len(), 16);
+        assert!(keys.get(4).is_none());
+
+        for (text, line) in [
+            ("1 MD5", 1),
+            ("\n0 MD5 x", 2),
+            ("1 RC4 x", 1),
+            ("1 AES128 short", 1),
+        ] {
+            match Keys::parse(text) {
+                Err(KeysError::Invalid { line: at, .. }) => assert_eq!(at, line, "{text}"),
+                other => panic!("{text}: {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn aes_cmac_test_vector() {
+        // see: https://datatracker.ietf.org/doc/html/rfc4493#section-4
+        let keys = Keys::parse(KEYS).unwrap();
+        assert_eq!(
+            keys.get(3).unwrap().mac(b""),
+            [
+                0xbb, 0x1d, 0x69, 0x29, 0xe9, 0x59, 0x37, 0x28, 0x7f, 0xa3, 0x7d, 0x12, 0x9b, 0x75,
+                0x67, 0x46
+            ]
+        );
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let keys = Keys::parse(KEYS).unwrap();
+        let packet = [7; NTP_MESSAGE_LENGTH];
+
+        for id in 1..=3 {
+            let key = keys.get(id).unwrap();
+            let signed = key.sign(&packet);
+            assert_eq!(
+                signed.len(),
+                NTP_MESSAGE_LENGTH + 4 + key.algorithm.mac_len()
+            );
+            key.verify_packet(&signed).unwrap();
+
+            let mut tampered = signed.clone();
+            tampered[10] ^= 1;
+            assert!(matches!(
+                key.verify_packet(&tampered),
+                Err(NtpError::BadMac)
+            ));
+        }
+
+        let md5 = keys.get(1).unwrap();
+        let other = Key::new(9, MacAlgorithm::Md5, b"secret".to_vec()).unwrap();
+        assert!(matches!(
+            md5.verify_packet(&other.sign(&packet)),
+            Err(NtpError::BadMac)
+        ));
+        assert!(matches!(md5.verify_packet(&packet), Err(NtpError::BadMac)));
+        assert!(matches!(
+            md5.verify_packet(&Authenticator::crypto_nak(&packet)),
+            Err(NtpError::CryptoNak)
+        ));
+    }
+}
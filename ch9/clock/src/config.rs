@@ -0,0 +1,270 @@
+use std::{
+    error::Error,
+    fmt::Display,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::Deserialize;
+
+/// Queried when neither `--server` nor the config file name any
+pub const DEFAULT_SERVERS: [&str; 4] = [
+    "time.google.com",
+    "time.cloudflare.com",
+    "time.apple.com",
+    "time.nist.gov",
+];
+
+/// How servers are queried unless they say otherwise
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    pub port: u16,
+    pub timeout: Duration,
+    /// Queries sent again after the first one failed
+    pub retries: u32,
+    /// ID in the keys file of the key authenticating queries
+    pub key: Option<u32>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            port: 123,
+            timeout: Duration::from_secs(1),
+            retries: 0,
+            key: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Server {
+    pub host: String,
+    pub port: u16,
+    pub timeout: Duration,
+    pub retries: u32,
+    pub key: Option<u32>,
+}
+
+/// A config file like
+///
+/// ```toml
+/// timeout_ms = 500
+/// keys = "/etc/ntp.keys"
+///
+/// [[server]]
+/// host = "time.google.com"
+///
+/// [[server]]
+/// host = "192.168.1.10"
+/// port = 1123
+/// retries = 2
+/// key = 1
+/// ```
+///
+/// where the top level settings apply to servers that do not set their own
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    port: Option<u16>,
+    timeout_ms: Option<u64>,
+    retries: Option<u32>,
+    key: Option<u32>,
+    /// Keys file of the key IDs
+    pub keys: Option<PathBuf>,
+    #[serde(default)]
+    server: Vec<ServerEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ServerEntry {
+    host: String,
+    port: Option<u16>,
+    timeout_ms: Option<u64>,
+    retries: Option<u32>,
+    key: Option<u32>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    Toml(toml::de::Error),
+    InvalidServer(String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(err) => write!(f, "{err}"),
+            ConfigError::Toml(err) => write!(f, "{err}"),
+            ConfigError::InvalidServer(spec) => {
+                write!(f, "invalid server {spec:?}, expected host[:port]")
+            }
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConfigError::Io(err) => Some(err),
+            ConfigError::Toml(err) => Some(err),
+            ConfigError::InvalidServer(_) => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ConfigError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(value: toml::de::Error) -> Self {
+        Self::Toml(value)
+    }
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(toml: &str) -> Result<Self, ConfigError> {
+        Ok(toml::from_str(toml)?)
+    }
+
+    /// `base` with the top level settings of the file applied
+    pub fn settings(&self, base: Settings) -> Settings {
+        Settings {
+            port: self.port.unwrap_or(base.port),
+            timeout: self.timeout_ms.map_or(base.timeout, Duration::from_millis),
+            retries: self.retries.unwrap_or(base.retries),
+            key: self.key.or(base.key),
+        }
+    }
+
+    /// Servers of the file, falling back to `settings` for what they leave out
+    pub fn servers(&self, settings: Settings) -> Vec<Server> {
+        self.server
+            .iter()
+            .map(|entry| Server {
+                host: entry.host.clone(),
+                port: entry.port.unwrap_or(settings.port),
+                timeout: entry
+                    .timeout_ms
+                    .map_or(settings.timeout, Duration::from_millis),
+                retries: entry.retries.unwrap_or(settings.retries),
+                key: entry.key.or(settings.key),
+            })
+            .collect()
+    }
+}
+
+impl Display for Server {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.host.contains(':') {
+            true => write!(f, "[{}]:{}", self.host, self.port),
+            false => write!(f, "{}:{}", self.host, self.port),
+        }
+    }
+}
+
+impl Server {
+    pub fn new(host: &str, settings: Settings) -> Self {
+        Self {
+            host: host.to_string(),
+            port: settings.port,
+            timeout: settings.timeout,
+            retries: settings.retries,
+            key: settings.key,
+        }
+    }
+
+    /// Parse `host`, `host:port`, `[ipv6]` or `[ipv6]:port`
+    pub fn parse(spec: &str, settings: Settings) -> Result<Self, ConfigError> {
+        let invalid = || ConfigError::InvalidServer(spec.to_string());
+
+        let (host, port) = if let Some(rest) = spec.strip_prefix('[') {
+            let (host, rest) = rest.split_once(']').ok_or_else(invalid)?;
+            match rest {
+                "" => (host, None),
+                _ => (host, Some(rest.strip_prefix(':').ok_or_else(invalid)?)),
+            }
+        } else {
+            match spec.split_once(':') {
+                // a bare IPv6 address has more than one colon
+                Some((host, port)) if !port.contains(':') => (host, Some(port)),
+                _ => (spec, None),
+            }
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+
+        let mut server = Server::new(host, settings);
+        if let Some(port) = port {
+            server.port = port.parse().map_err(|_| invalid())?;
+        }
+        Ok(server)
+    }
+}
+
+#[cfg(test)]
+mod config_test {
+    use super::*;
+
+    #[test]
+    fn file_settings_and_servers() {
+        let file = ConfigFile::parse(
+            r#"
+            timeout_ms = 500
+
+            [[server]]
+            host = "time.google.com"
+
+            [[server]]
+            host = "192.168.1.10"
+            port = 1123
+            retries = 2
+            key = 7
+            "#,
+        )
+        .unwrap();
+
+        let settings = file.settings(Settings::default());
+        assert_eq!(settings.timeout, Duration::from_millis(500));
+        let servers = file.servers(settings);
+        assert_eq!(servers[0], Server::new("time.google.com", settings));
+        assert_eq!(
+            (
+                servers[1].port,
+                servers[1].timeout,
+                servers[1].retries,
+                servers[1].key
+            ),
+            (1123, Duration::from_millis(500), 2, Some(7))
+        );
+
+        assert!(matches!(
+            ConfigFile::parse("[[server]]\nname = \"x\""),
+            Err(ConfigError::Toml(_))
+        ));
+    }
+
+    #[test]
+    fn server_specs() {
+        let settings = Settings::default();
+        let parse = |spec| Server::parse(spec, settings).map(|s| (s.host, s.port));
+
+        assert_eq!(parse("pool.ntp.org").unwrap(), ("pool.ntp.org".into(), 123));
+        assert_eq!(parse("127.0.0.1:1230").unwrap(), ("127.0.0.1".into(), 1230));
+        assert_eq!(parse("::1").unwrap(), ("::1".into(), 123));
+        assert_eq!(parse("[::1]:1230").unwrap(), ("::1".into(), 1230));
+        assert!(parse("host:port").is_err());
+        assert!(parse("[::1").is_err());
+        assert!(parse(":123").is_err());
+    }
+}
@@ -0,0 +1,288 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+use color_eyre::{eyre::Context, Report};
+use tracing::{info, warn};
+
+use crate::{
+    auth::Keys,
+    config::Server,
+    ntp,
+    report::{Correction, SyncReport},
+    Clock,
+};
+
+/// Largest frequency correction the kernel accepts, in ppm
+const MAX_FREQUENCY: f64 = 500.0;
+
+/// Share of each measured frequency error added to the estimate, smaller
+/// values settle slower but follow noisy offsets less
+const FREQUENCY_GAIN: f64 = 0.5;
+
+/// Offsets within this many milliseconds lengthen the poll interval,
+/// larger ones shorten it
+const STABLE_OFFSET_MS: f64 = 5.0;
+
+pub struct Options {
+    pub servers: Vec<Server>,
+    pub keys: Keys,
+    pub min_survivors: usize,
+    pub step_threshold: f64,
+    pub drift_file: Option<PathBuf>,
+    /// Where every poll is recorded for `status`
+    pub state_file: PathBuf,
+    /// Shortest and longest poll intervals as powers of two seconds
+    pub min_poll: u8,
+    pub max_poll: u8,
+    pub dry_run: bool,
+}
+
+/// Frequency error estimate of the local oscillator and the poll interval
+/// that follows how well the clock keeps time
+#[derive(Debug, Clone, PartialEq)]
+pub struct Discipline {
+    /// Frequency correction in ppm, positive speeds the clock up
+    pub frequency: f64,
+    /// Poll interval as a power of two seconds
+    pub poll: u8,
+    min_poll: u8,
+    max_poll: u8,
+}
+
+impl Discipline {
+    pub fn new(min_poll: u8, max_poll: u8) -> Self {
+        Self {
+            frequency: 0.0,
+            poll: min_poll,
+            min_poll,
+            max_poll,
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(1 << self.poll)
+    }
+
+    /// Account for `offset` in milliseconds, measured `since_correction`
+    /// after the previous offset was corrected. Without a previous
+    /// correction only the poll interval adapts.
+    pub fn update(&mut self, offset: f64, since_correction: Option<Duration>) {
+        if let Some(elapsed) = since_correction.filter(|elapsed| !elapsed.is_zero()) {
+            // whatever built up since the last correction is left by the
+            // frequency error the current correction misses
+            let residual = offset / elapsed.as_secs_f64() * 1000.0;
+            self.frequency =
+                (self.frequency + residual * FREQUENCY_GAIN).clamp(-MAX_FREQUENCY, MAX_FREQUENCY);
+        }
+
+        self.poll = match offset.abs() <= STABLE_OFFSET_MS {
+            true => (self.poll + 1).min(self.max_poll),
+            false => self.poll.saturating_sub(1).max(self.min_poll),
+        };
+    }
+
+    /// Poll again soon after no usable offset came back
+    pub fn unreachable(&mut self) {
+        self.poll = self.min_poll;
+    }
+}
+
+/// When the previous correction was made and how long the kernel takes
+/// to slew it, zero for a step
+type LastCorrection = (Instant, Duration);
+
+fn last_correction(correction: &Correction) -> LastCorrection {
+    let slewing = match correction {
+        Correction::Slew { duration_s, .. } => Duration::from_secs_f64(*duration_s),
+        Correction::Step { .. } => Duration::ZERO,
+    };
+    (Instant::now(), slewing)
+}
+
+/// Time since the previous correction once it is fully applied. While
+/// a slew still runs, part of the offset is the slew left to do rather
+/// than frequency error, so nothing can be estimated yet.
+fn since_applied(last: Option<LastCorrection>, now: Instant) -> Option<Duration> {
+    let (at, slewing) = last?;
+    let elapsed = now.saturating_duration_since(at);
+    (elapsed >= slewing).then_some(elapsed)
+}
+
+/// Frequency correction in ppm kept in `path`, like ntpd's driftfile
+pub fn read_drift(path: &Path) -> Result<Option<f64>, Report> {
+    match fs::read_to_string(path) {
+        Ok(text) => text
+            .trim()
+            .parse()
+            .map(Some)
+            .wrap_err_with(|| format!("invalid drift file {}", path.display())),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+pub fn write_drift(path: &Path, frequency: f64) -> Result<(), std::io::Error> {
+    // replace the file at once so a crash never leaves half of it
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, format!("{frequency:.3}\n"))?;
+    fs::rename(&tmp, path)
+}
+
+/// Keep the clock in sync with the servers: correct the offset after every
+/// poll and the frequency error estimated from how fast it builds up again
+pub fn run(options: &Options) -> Result<(), Report> {
+    let mut discipline = Discipline::new(options.min_poll, options.max_poll);
+
+    if let Some(path) = &options.drift_file {
+        if let Some(frequency) = read_drift(path)? {
+            info!(
+                frequency_ppm = frequency,
+                "loaded drift from {}",
+                path.display()
+            );
+            discipline.frequency = frequency;
+        }
+    }
+    Clock::set_frequency(discipline.frequency, options.dry_run)
+        .wrap_err("unable to correct the clock frequency")?;
+
+    let mut last: Option<LastCorrection> = None;
+    let mut leap_armed = crate::leap_armed(&options.state_file);
+    loop {
+        let (servers, offset) = ntp::check_time(
+            &options.servers,
+            &options.keys,
+            options.min_survivors,
+            false,
+        );
+        let mut report = SyncReport::new(servers, options.dry_run);
+        report.leap_armed = leap_armed;
+        match offset {
+            Ok(offset) => {
+                // a step means the clock was off for other reasons than drift
+                let since_correction = since_applied(last, Instant::now())
+                    .filter(|_| offset.abs() <= options.step_threshold);
+                discipline.update(offset, since_correction);
+                info!(
+                    offset_ms = offset,
+                    frequency_ppm = discipline.frequency,
+                    poll_s = discipline.interval().as_secs(),
+                    "synced"
+                );
+
+                // a failure here may pass by the next poll, so it only gets logged
+                if let Err(err) = Clock::set_frequency(discipline.frequency, options.dry_run) {
+                    warn!("unable to correct the clock frequency: {err}");
+                }
+                let result = crate::correct(offset, true, options.step_threshold, options.dry_run);
+                report.offset_ms = Some(offset);
+                report.corrected(&result);
+                match result {
+                    Ok(correction) => {
+                        if options.dry_run {
+                            crate::print_dry_run(&correction, report.leap);
+                        }
+                        if let Err(err) = crate::honour_leap(&mut report, options.dry_run) {
+                            warn!("{err}: {}", err.root_cause());
+                        }
+                        last = Some(last_correction(&correction));
+
+                        // a dry run corrects nothing, so its estimate is meaningless
+                        if let Some(path) = options.drift_file.as_ref().filter(|_| !options.dry_run)
+                        {
+                            if let Err(err) = write_drift(path, discipline.frequency) {
+                                warn!("unable to write drift file {}: {err}", path.display());
+                            }
+                        }
+                    }
+                    Err(err) => warn!("{err}: {}", err.root_cause()),
+                }
+            }
+            Err(err) => {
+                warn!("no usable offset: {err}");
+                report.error = Some(err.to_string());
+                discipline.unreachable();
+            }
+        }
+        leap_armed = report.leap_armed;
+        report.frequency_ppm = Some(discipline.frequency);
+        report.poll_s = Some(discipline.interval().as_secs());
+        crate::save_state(&report, &options.state_file);
+        thread::sleep(discipline.interval());
+    }
+}
+
+#[cfg(test)]
+mod daemon_test {
+    use super::*;
+
+    #[test]
+    fn estimates_frequency() {
+        let mut discipline = Discipline::new(6, 10);
+
+        // the first offset says nothing about the frequency
+        discipline.update(40.0, None);
+        assert_eq!(discipline.frequency, 0.0);
+        assert_eq!(discipline.poll, 6);
+
+        // 10ms over 1000s is 10ppm slow, half of it is taken at once
+        discipline.update(10.0, Some(Duration::from_secs(1000)));
+        assert!((discipline.frequency - 5.0).abs() < 1e-9);
+        discipline.update(5.0, Some(Duration::from_secs(1000)));
+        assert!((discipline.frequency - 7.5).abs() < 1e-9);
+
+        discipline.update(1e6, Some(Duration::from_secs(1)));
+        assert_eq!(discipline.frequency, MAX_FREQUENCY);
+    }
+
+    #[test]
+    fn waits_for_slew() {
+        let now = Instant::now();
+        assert_eq!(since_applied(None, now), None);
+
+        // 128ms at 500ppm takes 256s, so a poll after 64s falls inside it
+        let slewing = Duration::from_secs(256);
+        let at = now - Duration::from_secs(64);
+        assert_eq!(since_applied(Some((at, slewing)), now), None);
+        let at = now - Duration::from_secs(300);
+        assert_eq!(
+            since_applied(Some((at, slewing)), now),
+            Some(Duration::from_secs(300))
+        );
+        assert_eq!(
+            since_applied(Some((at, Duration::ZERO)), now),
+            Some(Duration::from_secs(300))
+        );
+    }
+
+    #[test]
+    fn adapts_poll_interval() {
+        let mut discipline = Discipline::new(6, 8);
+        for _ in 0..5 {
+            discipline.update(1.0, None);
+        }
+        assert_eq!(discipline.interval(), Duration::from_secs(256));
+
+        discipline.update(-20.0, None);
+        assert_eq!(discipline.poll, 7);
+        discipline.unreachable();
+        assert_eq!(discipline.poll, 6);
+    }
+
+    #[test]
+    fn drift_file_roundtrip() {
+        let path = std::env::temp_dir().join(format!("clock-drift-{}", std::process::id()));
+        assert_eq!(read_drift(&path).unwrap(), None);
+
+        write_drift(&path, -12.3456).unwrap();
+        assert_eq!(read_drift(&path).unwrap(), Some(-12.346));
+
+        fs::write(&path, "fast").unwrap();
+        assert!(read_drift(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,359 @@
+use std::{fmt::Display, str::FromStr};
+
+use chrono::{
+    format::StrftimeItems, DateTime, Local, LocalResult, NaiveDate, NaiveDateTime, NaiveTime,
+    TimeZone, Utc,
+};
+use chrono_tz::Tz;
+
+use crate::ClockError;
+
+/// Names accepted by `--format`
+pub const FORMATS: [&str; 8] = [
+    "auto",
+    "unix",
+    "timestamp",
+    "micros",
+    "nanos",
+    "iso-week",
+    "rfc2822",
+    "rfc3339",
+];
+
+// see: https://en.wikipedia.org/wiki/ISO_week_date
+const ISO_WEEK: &str = "%G-W%V-%uT%H:%M:%S%.f%:z";
+
+/// Patterns tried after the RFCs when guessing, times without an offset
+/// are in the zone given
+const GUESSED_PATTERNS: [&str; 10] = [
+    "%Y-%m-%d %H:%M:%S%.f%:z",
+    "%Y-%m-%d %H:%M:%S%.f %z",
+    "%Y-%m-%dT%H:%M:%S%.f",
+    "%Y-%m-%d %H:%M:%S%.f",
+    "%Y-%m-%d %H:%M",
+    "%Y-%m-%d",
+    "%Y/%m/%d %H:%M:%S%.f",
+    "%Y/%m/%d",
+    "%G-W%V-%uT%H:%M:%S%.f",
+    "%G-W%V-%u",
+];
+
+/// How datetimes are read and written
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Format {
+    /// Guess when reading, RFC 3339 when writing
+    Auto,
+    /// Seconds since the Unix epoch
+    Unix,
+    /// Milliseconds since the Unix epoch
+    Timestamp,
+    Micros,
+    Nanos,
+    IsoWeek,
+    Rfc2822,
+    Rfc3339,
+    Strftime(String),
+}
+
+impl FromStr for Format {
+    type Err = ClockError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "auto" => Ok(Format::Auto),
+            "unix" => Ok(Format::Unix),
+            "timestamp" => Ok(Format::Timestamp),
+            "micros" => Ok(Format::Micros),
+            "nanos" => Ok(Format::Nanos),
+            "iso-week" => Ok(Format::IsoWeek),
+            "rfc2822" => Ok(Format::Rfc2822),
+            "rfc3339" => Ok(Format::Rfc3339),
+            _ => Err(ClockError::Custom(format!("unknown format {name:?}"))),
+        }
+    }
+}
+
+impl Format {
+    /// A strftime pattern, checked up front as chrono panics on invalid ones
+    // see: https://docs.rs/chrono/latest/chrono/format/strftime/index.html
+    pub fn strftime(pattern: &str) -> Result<Self, ClockError> {
+        StrftimeItems::new(pattern).parse()?;
+        Ok(Format::Strftime(pattern.to_string()))
+    }
+}
+
+/// Time zone datetimes are written in, and read in when they carry no offset
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    Local,
+    Utc,
+    Named(Tz),
+}
+
+impl Display for Zone {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Zone::Local => write!(f, "the local time zone"),
+            Zone::Utc => write!(f, "UTC"),
+            Zone::Named(tz) => write!(f, "{tz}"),
+        }
+    }
+}
+
+impl Zone {
+    fn localize(self, naive: NaiveDateTime) -> Result<DateTime<Utc>, ClockError> {
+        let result = match self {
+            Zone::Local => Local.from_local_datetime(&naive).map(|dt| dt.to_utc()),
+            Zone::Utc => LocalResult::Single(naive.and_utc()),
+            Zone::Named(tz) => tz.from_local_datetime(&naive).map(|dt| dt.to_utc()),
+        };
+        match result {
+            LocalResult::Single(dt) => Ok(dt),
+            LocalResult::Ambiguous(earliest, latest) => Err(ClockError::Custom(format!(
+                "{naive} is ambiguous in {self}, either {earliest} or {latest}"
+            ))),
+            LocalResult::None => Err(ClockError::Custom(format!(
+                "{naive} does not exist in {self}"
+            ))),
+        }
+    }
+}
+
+fn render<Tz: TimeZone>(dt: DateTime<Tz>, format: &Format) -> String
+where
+    Tz::Offset: Display,
+{
+    match format {
+        Format::Rfc2822 => dt.to_rfc2822(),
+        Format::IsoWeek => dt.format(ISO_WEEK).to_string(),
+        Format::Strftime(pattern) => dt.format(pattern).to_string(),
+        _ => dt.to_rfc3339(),
+    }
+}
+
+/// `dt` written in `format`, in `zone` unless counted from the Unix epoch
+pub fn format(dt: DateTime<Utc>, format: &Format, zone: Zone) -> String {
+    match format {
+        Format::Unix => dt.timestamp().to_string(),
+        Format::Timestamp => dt.timestamp_millis().to_string(),
+        Format::Micros => dt.timestamp_micros().to_string(),
+        // i64 nanoseconds only reach from 1677 to 2262
+        Format::Nanos => (dt.timestamp() as i128 * 1_000_000_000
+            + dt.timestamp_subsec_nanos() as i128)
+            .to_string(),
+        _ => match zone {
+            Zone::Local => render(dt.with_timezone(&Local), format),
+            Zone::Utc => render(dt, format),
+            Zone::Named(tz) => render(dt.with_timezone(&tz), format),
+        },
+    }
+}
+
+/// `value` counted in `1 / per_second` seconds since the Unix epoch
+fn from_units(value: i64, per_second: i64) -> Result<DateTime<Utc>, ClockError> {
+    let secs = value.div_euclid(per_second);
+    let nanos = value.rem_euclid(per_second) * (1_000_000_000 / per_second);
+    DateTime::from_timestamp(secs, nanos as u32)
+        .ok_or_else(|| ClockError::Custom(format!("timestamp {value} is out of range")))
+}
+
+/// `text` in `pattern`, which may leave out the offset or the time of day
+fn parse_pattern(text: &str, pattern: &str, zone: Zone) -> Result<DateTime<Utc>, ClockError> {
+    if let Ok(dt) = DateTime::parse_from_str(text, pattern) {
+        return Ok(dt.to_utc());
+    }
+    match NaiveDateTime::parse_from_str(text, pattern) {
+        Ok(naive) => zone.localize(naive),
+        Err(err) => match NaiveDate::parse_from_str(text, pattern) {
+            Ok(date) => zone.localize(date.and_time(NaiveTime::MIN)),
+            Err(_) => Err(err.into()),
+        },
+    }
+}
+
+/// Integers are counted from the Unix epoch in the unit that puts them
+/// before the year 5000, decimals are seconds
+fn guess_number(text: &str) -> Option<Result<DateTime<Utc>, ClockError>> {
+    let digits = text.strip_prefix('-').unwrap_or(text);
+    let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+    let is_digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+    if whole.is_empty() || !is_digits(whole) || !is_digits(fraction) || fraction.len() > 9 {
+        return None;
+    }
+
+    if text.contains('.') {
+        let nanos = format!("{fraction:0<9}").parse::<i64>().unwrap();
+        let result = text[..text.len() - fraction.len() - 1]
+            .parse::<i64>()
+            .map_err(ClockError::from)
+            .and_then(|secs| {
+                // counted apart, seconds as nanoseconds overflow after 2262
+                let (secs, nanos) = match text.starts_with('-') && nanos > 0 {
+                    true => (secs.checked_sub(1), 1_000_000_000 - nanos),
+                    false => (Some(secs), nanos),
+                };
+                secs.and_then(|secs| DateTime::from_timestamp(secs, nanos as u32))
+                    .ok_or_else(|| ClockError::Custom(format!("timestamp {text} is out of range")))
+            });
+        return Some(result);
+    }
+
+    let result = text
+        .parse::<i64>()
+        .map_err(ClockError::from)
+        .and_then(|value| {
+            let per_second = match value.unsigned_abs() {
+                0..100_000_000_000 => 1,
+                100_000_000_000..100_000_000_000_000 => 1_000,
+                100_000_000_000_000..100_000_000_000_000_000 => 1_000_000,
+                _ => 1_000_000_000,
+            };
+            from_units(value, per_second)
+        });
+    Some(result)
+}
+
+/// `text` in the first format that fits it
+fn guess(text: &str, zone: Zone) -> Result<DateTime<Utc>, ClockError> {
+    if let Some(result) = guess_number(text) {
+        return result;
+    }
+    if let Ok(dt) = DateTime::parse_from_rfc3339(text) {
+        return Ok(dt.to_utc());
+    }
+    if let Ok(dt) = DateTime::parse_from_rfc2822(text) {
+        return Ok(dt.to_utc());
+    }
+    if let Ok(dt) = DateTime::parse_from_str(text, ISO_WEEK) {
+        return Ok(dt.to_utc());
+    }
+    // a pattern that fits but names no single instant in `zone` ends the guessing
+    GUESSED_PATTERNS
+        .iter()
+        .map(|pattern| parse_pattern(text, pattern, zone))
+        .find(|result| !matches!(result, Err(ClockError::ChronoParse(_))))
+        .unwrap_or_else(|| {
+            Err(ClockError::Custom(format!(
+                "unable to guess the format of {text:?}"
+            )))
+        })
+}
+
+/// Read `text` in `format`, times without an offset are in `zone`
+pub fn parse(text: &str, format: &Format, zone: Zone) -> Result<DateTime<Utc>, ClockError> {
+    let text = text.trim();
+    match format {
+        Format::Auto => guess(text, zone),
+        Format::Unix => from_units(text.parse()?, 1),
+        Format::Timestamp => from_units(text.parse()?, 1_000),
+        Format::Micros => from_units(text.parse()?, 1_000_000),
+        Format::Nanos => from_units(text.parse()?, 1_000_000_000),
+        Format::Rfc2822 => Ok(DateTime::parse_from_rfc2822(text)?.to_utc()),
+        Format::Rfc3339 => Ok(DateTime::parse_from_rfc3339(text)?.to_utc()),
+        Format::IsoWeek => match DateTime::parse_from_str(text, ISO_WEEK) {
+            Ok(dt) => Ok(dt.to_utc()),
+            // without an offset
+            Err(_) => parse_pattern(text, &ISO_WEEK[..ISO_WEEK.len() - 3], zone),
+        },
+        Format::Strftime(pattern) => parse_pattern(text, pattern, zone),
+    }
+}
+
+#[cfg(test)]
+mod format_test {
+    use super::*;
+
+    fn utc(text: &str) -> DateTime<Utc> {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn roundtrip_every_format() {
+        let dt = utc("2024-03-10T12:34:56.789Z");
+        let berlin = Zone::Named(chrono_tz::Europe::Berlin);
+        for name in FORMATS {
+            let format: Format = name.parse().unwrap();
+            for zone in [Zone::Utc, berlin] {
+                let text = super::format(dt, &format, zone);
+                let back = parse(&text, &format, zone).unwrap();
+                // RFC 2822 has no fractional seconds
+                let expected = match format {
+                    Format::Rfc2822 | Format::Unix => utc("2024-03-10T12:34:56Z"),
+                    _ => dt,
+                };
+                assert_eq!(back, expected, "{name} {text}");
+            }
+        }
+
+        assert_eq!(
+            super::format(dt, &Format::Nanos, Zone::Utc),
+            "1710074096789000000"
+        );
+        assert_eq!(
+            super::format(dt, &Format::IsoWeek, berlin),
+            "2024-W10-7T13:34:56.789+01:00"
+        );
+    }
+
+    #[test]
+    fn strftime_in_zones() {
+        let format = Format::strftime("%d.%m.%Y %H:%M").unwrap();
+        let tokyo = Zone::Named("Asia/Tokyo".parse().unwrap());
+        let dt = utc("2024-03-10T12:34:00Z");
+        assert_eq!(super::format(dt, &format, tokyo), "10.03.2024 21:34");
+        assert_eq!(parse("10.03.2024 21:34", &format, tokyo).unwrap(), dt);
+
+        assert!(Format::strftime("%Y-%Q").is_err());
+    }
+
+    #[test]
+    fn guesses_formats() {
+        let dt = utc("2024-03-10T12:34:56Z");
+        for text in [
+            "1710074096",
+            "1710074096000",
+            "1710074096000000",
+            "1710074096000000000",
+            "1710074096.0",
+            "2024-03-10T12:34:56Z",
+            "2024-03-10T13:34:56+01:00",
+            "Sun, 10 Mar 2024 12:34:56 +0000",
+            "2024-03-10 12:34:56",
+            "2024-W10-7T12:34:56",
+        ] {
+            assert_eq!(parse(text, &Format::Auto, Zone::Utc).unwrap(), dt, "{text}");
+        }
+        assert_eq!(
+            parse("-1.5", &Format::Auto, Zone::Utc).unwrap(),
+            utc("1969-12-31T23:59:58.5Z")
+        );
+        assert_eq!(
+            parse("-0.25", &Format::Auto, Zone::Utc).unwrap(),
+            utc("1969-12-31T23:59:59.75Z")
+        );
+        // past what nanoseconds since the epoch can count
+        assert_eq!(
+            parse("10000000000.5", &Format::Auto, Zone::Utc).unwrap(),
+            utc("2286-11-20T17:46:40.5Z")
+        );
+        assert!(parse("99999999999999999.5", &Format::Auto, Zone::Utc).is_err());
+        assert_eq!(
+            parse("2024-03-10", &Format::Auto, Zone::Utc).unwrap(),
+            utc("2024-03-10T00:00:00Z")
+        );
+        assert!(parse("next tuesday", &Format::Auto, Zone::Utc).is_err());
+    }
+
+    #[test]
+    fn local_times_that_do_not_exist() {
+        let berlin = Zone::Named(chrono_tz::Europe::Berlin);
+        // clocks jumped from 2:00 to 3:00 and fell back from 3:00 to 2:00
+        let error = |text| parse(text, &Format::Auto, berlin).unwrap_err().to_string();
+        assert!(error("2024-03-31 02:30").contains("does not exist"));
+        assert!(error("2024-10-27 02:30").contains("ambiguous"));
+        assert_eq!(
+            parse("2024-03-31 03:30", &Format::Auto, berlin).unwrap(),
+            utc("2024-03-31T01:30:00Z")
+        );
+    }
+}
@@ -0,0 +1,403 @@
+use std::{cmp::Ordering, error::Error, fmt::Display, path::Path};
+
+use chrono::{DateTime, Duration, NaiveDateTime, NaiveTime, SecondsFormat, Timelike, Utc};
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+
+use crate::packet::{LeapIndicator, NTPTimestamp};
+
+/// Where tzdata installs the list
+pub const DEFAULT_LEAP_FILE: &str = "/usr/share/zoneinfo/leap-seconds.list";
+
+/// A change of TAI - UTC, always at midnight UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct LeapSecond {
+    /// First second with the new offset, the leap second comes right before it
+    pub at: DateTime<Utc>,
+    /// TAI - UTC in seconds from `at` on
+    pub tai_offset: i32,
+}
+
+/// The leap seconds of the IETF `leap-seconds.list`, which lists one
+/// `NTP_SECONDS TAI_MINUS_UTC` per line along with
+///
+/// ```text
+/// #$ NTP seconds of the last update
+/// #@ NTP seconds the list expires at
+/// #h SHA-1 of the numbers of the other lines, in hex
+/// ```
+// see: https://data.iana.org/time-zones/tzdb/leap-seconds.list
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeapTable {
+    pub updated: DateTime<Utc>,
+    pub expires: DateTime<Utc>,
+    pub leaps: Vec<LeapSecond>,
+}
+
+#[derive(Debug)]
+pub enum LeapError {
+    Io(std::io::Error),
+    Invalid {
+        line: usize,
+        reason: String,
+    },
+    Missing(&'static str),
+    /// The list was altered or cut short
+    BadHash,
+}
+
+impl Display for LeapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LeapError::Io(err) => write!(f, "{err}"),
+            LeapError::Invalid { line, reason } => write!(f, "line {line}: {reason}"),
+            LeapError::Missing(what) => write!(f, "no {what} line"),
+            LeapError::BadHash => write!(f, "the hash does not match the list"),
+        }
+    }
+}
+
+impl Error for LeapError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LeapError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for LeapError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+fn ntp_seconds(seconds: u32) -> DateTime<Utc> {
+    NTPTimestamp {
+        seconds,
+        fraction: 0,
+    }
+    .into()
+}
+
+impl LeapTable {
+    pub fn load(path: &Path) -> Result<Self, LeapError> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, LeapError> {
+        let (mut updated, mut expires, mut hash) = (None, None, None);
+        let mut leaps = Vec::new();
+        // the hash covers the update and expiry times and the leap lines
+        let mut hashed = Sha1::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let invalid = |reason: String| LeapError::Invalid {
+                line: i + 1,
+                reason,
+            };
+            let seconds = |text: Option<&str>| {
+                let text = text.unwrap_or_default();
+                text.parse::<u32>()
+                    .map(|seconds| (text.to_string(), seconds))
+                    .map_err(|_| invalid(format!("invalid NTP seconds {text:?}")))
+            };
+
+            if let Some(rest) = line.strip_prefix("#$") {
+                let (text, value) = seconds(rest.split_whitespace().next())?;
+                hashed.update(text);
+                updated = Some(ntp_seconds(value));
+            } else if let Some(rest) = line.strip_prefix("#@") {
+                let (text, value) = seconds(rest.split_whitespace().next())?;
+                hashed.update(text);
+                expires = Some(ntp_seconds(value));
+            } else if let Some(rest) = line.strip_prefix("#h") {
+                hash = Some(rest.split_whitespace().collect::<String>().to_lowercase());
+            } else if !line.starts_with('#') && !line.trim().is_empty() {
+                let mut fields = line
+                    .split_whitespace()
+                    .take_while(|field| !field.starts_with('#'));
+                let (at_text, at) = seconds(fields.next())?;
+                let offset_text = fields.next().unwrap_or_default();
+                let tai_offset = offset_text
+                    .parse()
+                    .map_err(|_| invalid(format!("invalid TAI - UTC {offset_text:?}")))?;
+                hashed.update(at_text);
+                hashed.update(offset_text);
+                let at = ntp_seconds(at);
+                if leaps.last().is_some_and(|last: &LeapSecond| last.at >= at) {
+                    return Err(invalid("leap seconds are out of order".to_string()));
+                }
+                leaps.push(LeapSecond { at, tai_offset });
+            }
+        }
+
+        let digest: String = hashed
+            .finalize()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        // lists without a hash are taken as they are, like ntpd does
+        if hash.is_some_and(|hash| hash != digest) {
+            return Err(LeapError::BadHash);
+        }
+        Ok(Self {
+            updated: updated.ok_or(LeapError::Missing("#$ update time"))?,
+            expires: expires.ok_or(LeapError::Missing("#@ expiry time"))?,
+            leaps,
+        })
+    }
+
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        now >= self.expires
+    }
+
+    /// TAI - UTC in seconds at `utc`, unknown before 1972
+    pub fn tai_offset(&self, utc: DateTime<Utc>) -> Option<i32> {
+        self.leaps
+            .iter()
+            .take_while(|leap| leap.at <= utc)
+            .last()
+            .map(|leap| leap.tai_offset)
+    }
+
+    /// TAI at `utc`, which may be in a leap second
+    pub fn utc_to_tai(&self, utc: DateTime<Utc>) -> Option<NaiveDateTime> {
+        let nanos = utc.timestamp_subsec_nanos();
+        if nanos >= 1_000_000_000 {
+            // chrono keeps 23:59:60 as 23:59:59 with over a billion nanoseconds,
+            // TAI counts it as the second before midnight with the offset after it
+            let midnight = utc.with_nanosecond(nanos - 1_000_000_000)? + Duration::seconds(1);
+            let offset = self.tai_offset(midnight)?;
+            return Some(midnight.naive_utc() + Duration::seconds(offset as i64 - 1));
+        }
+        let offset = self.tai_offset(utc)?;
+        Some(utc.naive_utc() + Duration::seconds(offset.into()))
+    }
+
+    /// UTC at `tai`, giving inserted leap seconds as 23:59:60
+    pub fn tai_to_utc(&self, tai: NaiveDateTime) -> Option<DateTime<Utc>> {
+        let mut previous: Option<i32> = None;
+        let mut utc = None;
+        for leap in &self.leaps {
+            let starts = leap.at.naive_utc() + Duration::seconds(leap.tai_offset.into());
+            if tai >= starts {
+                utc = Some((tai - Duration::seconds(leap.tai_offset.into())).and_utc());
+            } else {
+                // the seconds TAI counts while UTC repeats the last one
+                let inserted = leap.tai_offset - previous.unwrap_or(leap.tai_offset);
+                if inserted > 0 && tai >= starts - Duration::seconds(inserted.into()) {
+                    let into = tai - (starts - Duration::seconds(1));
+                    let last = (leap.at - Duration::seconds(1)).date_naive();
+                    let nanos = 1_000_000_000 + into.subsec_nanos() as u32;
+                    let time = NaiveTime::from_hms_nano_opt(23, 59, 59, nanos)?;
+                    utc = Some(last.and_time(time).and_utc());
+                }
+                break;
+            }
+            previous = Some(leap.tai_offset);
+        }
+        utc
+    }
+
+    /// Leap seconds after `now`
+    pub fn upcoming(&self, now: DateTime<Utc>) -> impl Iterator<Item = &LeapSecond> {
+        self.leaps.iter().filter(move |leap| leap.at > now)
+    }
+
+    /// What an NTP server announces on the day of a leap second
+    // see: https://datatracker.ietf.org/doc/html/rfc5905#section-7.3
+    pub fn leap_indicator(&self, now: DateTime<Utc>) -> LeapIndicator {
+        let today = self.tai_offset(now);
+        match self.upcoming(now).next() {
+            Some(leap) if leap.at - now <= Duration::days(1) => {
+                // a repeated entry changes nothing
+                match leap.tai_offset.cmp(&today.unwrap_or(leap.tai_offset)) {
+                    Ordering::Greater => LeapIndicator::AddSecond,
+                    Ordering::Less => LeapIndicator::DeleteSecond,
+                    Ordering::Equal => LeapIndicator::NoWarning,
+                }
+            }
+            _ => LeapIndicator::NoWarning,
+        }
+    }
+
+    pub fn status(&self, now: DateTime<Utc>) -> LeapStatus {
+        LeapStatus {
+            tai_offset: self.tai_offset(now),
+            expires: self.expires,
+            expired: self.is_expired(now),
+            upcoming: self.upcoming(now).copied().collect(),
+        }
+    }
+}
+
+/// What `status` shows of the leap seconds
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LeapStatus {
+    pub tai_offset: Option<i32>,
+    pub expires: DateTime<Utc>,
+    pub expired: bool,
+    pub upcoming: Vec<LeapSecond>,
+}
+
+impl Display for LeapStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.tai_offset {
+            Some(offset) => writeln!(f, "TAI - UTC       : {offset}s")?,
+            None => writeln!(f, "TAI - UTC       : unknown")?,
+        }
+        let expires = self.expires.format("%Y-%m-%d");
+        match self.upcoming.first() {
+            Some(leap) => write!(
+                f,
+                "Next leap       : {}, TAI - UTC becomes {}s",
+                leap.at.to_rfc3339_opts(SecondsFormat::Secs, true),
+                leap.tai_offset
+            )?,
+            None if self.expired => write!(
+                f,
+                "Next leap       : unknown, the list expired on {expires}"
+            )?,
+            None => write!(f, "Next leap       : none announced until {expires}")?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod leap_test {
+    use super::*;
+
+    /// The list as tzdata ships it, updated on 7 July 2025
+    const LEAP_SECONDS_LIST: &str = "\
+#	Updated through IERS Bulletin C (https://hpiers.obspm.fr/iers/bul/bulc/bulletinc.dat)
+#	File expires on 28 June 2026
+#
+#$	3960835200
+#@	3991593600
+#
+2272060800      10      # 1 Jan 1972
+2287785600      11      # 1 Jul 1972
+2303683200      12      # 1 Jan 1973
+2335219200      13      # 1 Jan 1974
+2366755200      14      # 1 Jan 1975
+2398291200      15      # 1 Jan 1976
+2429913600      16      # 1 Jan 1977
+2461449600      17      # 1 Jan 1978
+2492985600      18      # 1 Jan 1979
+2524521600      19      # 1 Jan 1980
+2571782400      20      # 1 Jul 1981
+2603318400      21      # 1 Jul 1982
+2634854400      22      # 1 Jul 1983
+2698012800      23      # 1 Jul 1985
+2776982400      24      # 1 Jan 1988
+2840140800      25      # 1 Jan 1990
+2871676800      26      # 1 Jan 1991
+2918937600      27      # 1 Jul 1992
+2950473600      28      # 1 Jul 1993
+2982009600      29      # 1 Jul 1994
+3029443200      30      # 1 Jan 1996
+3076704000      31      # 1 Jul 1997
+3124137600      32      # 1 Jan 1999
+3345062400      33      # 1 Jan 2006
+3439756800      34      # 1 Jan 2009
+3550089600      35      # 1 Jul 2012
+3644697600      36      # 1 Jul 2015
+3692217600      37      # 1 Jan 2017
+#
+#h	49db2447 571e5e1b 2f002a53 9c8da8e4 39b8e49e
+";
+
+    fn utc(text: &str) -> DateTime<Utc> {
+        text.parse().unwrap()
+    }
+
+    fn naive(text: &str) -> NaiveDateTime {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_leap_seconds_list() {
+        let table = LeapTable::parse(LEAP_SECONDS_LIST).unwrap();
+        assert_eq!(table.leaps.len(), 28);
+        assert_eq!(table.leaps[0].at, utc("1972-01-01T00:00:00Z"));
+        assert_eq!(table.expires, utc("2026-06-28T00:00:00Z"));
+        assert_eq!(table.tai_offset(utc("1971-12-31T23:59:59Z")), None);
+        assert_eq!(table.tai_offset(utc("2016-12-31T23:59:59Z")), Some(36));
+        assert_eq!(table.tai_offset(utc("2017-01-01T00:00:00Z")), Some(37));
+
+        let tampered = LEAP_SECONDS_LIST.replace("3692217600      37", "3692217600      38");
+        assert!(matches!(
+            LeapTable::parse(&tampered),
+            Err(LeapError::BadHash)
+        ));
+        assert!(matches!(
+            LeapTable::parse("#$ 3960835200\nsoon 38"),
+            Err(LeapError::Invalid { line: 2, .. })
+        ));
+        assert!(matches!(
+            LeapTable::parse("#$ 3960835200"),
+            Err(LeapError::Missing(_))
+        ));
+    }
+
+    #[test]
+    fn tai_utc_conversion() {
+        let table = LeapTable::parse(LEAP_SECONDS_LIST).unwrap();
+        // 2016-12-31 ended with 23:59:60
+        let cases = [
+            ("2016-12-31T23:59:59Z", "2017-01-01T00:00:35"),
+            ("2016-12-31T23:59:59.500Z", "2017-01-01T00:00:35.500"),
+            ("2017-01-01T00:00:00Z", "2017-01-01T00:00:37"),
+            ("2024-03-10T12:00:00Z", "2024-03-10T12:00:37"),
+        ];
+        for (utc_text, tai_text) in cases {
+            assert_eq!(table.utc_to_tai(utc(utc_text)), Some(naive(tai_text)));
+            assert_eq!(table.tai_to_utc(naive(tai_text)), Some(utc(utc_text)));
+        }
+
+        let leap_second = table.tai_to_utc(naive("2017-01-01T00:00:36.250")).unwrap();
+        assert_eq!(leap_second.to_rfc3339(), "2016-12-31T23:59:60.250+00:00");
+        assert_eq!(
+            table.utc_to_tai(leap_second),
+            Some(naive("2017-01-01T00:00:36.250"))
+        );
+        assert_eq!(table.tai_to_utc(naive("1972-01-01T00:00:09")), None);
+    }
+
+    #[test]
+    fn announces_upcoming_leaps() {
+        let table = LeapTable::parse(
+            "#$ 3960835200\n#@ 4000000000\n3692217600 37\n3976214400 38\n3991852800 37\n",
+        )
+        .unwrap();
+        let inserted = utc("2026-01-01T00:00:00Z");
+        assert_eq!(table.leaps[1].at, inserted);
+
+        let now = utc("2025-12-31T12:00:00Z");
+        assert_eq!(table.upcoming(now).count(), 2);
+        assert_eq!(table.leap_indicator(now), LeapIndicator::AddSecond);
+        assert_eq!(
+            table.leap_indicator(utc("2025-12-30T12:00:00Z")),
+            LeapIndicator::NoWarning
+        );
+        assert_eq!(
+            table.leap_indicator(utc("2026-06-30T12:00:00Z")),
+            LeapIndicator::DeleteSecond
+        );
+        let repeated =
+            LeapTable::parse("#$ 3960835200\n#@ 4000000000\n3692217600 37\n3976214400 37\n")
+                .unwrap();
+        assert_eq!(repeated.leap_indicator(now), LeapIndicator::NoWarning);
+
+        let status = table.status(now).to_string();
+        assert!(status.contains("TAI - UTC       : 37s\n"), "{status}");
+        assert!(status.ends_with("Next leap       : 2026-01-01T00:00:00Z, TAI - UTC becomes 38s"));
+        let status = table.status(utc("2027-01-01T00:00:00Z")).to_string();
+        assert!(
+            status.ends_with("unknown, the list expired on 2026-10-03"),
+            "{status}"
+        );
+    }
+}
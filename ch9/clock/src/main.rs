@@ -1,16 +1,42 @@
+mod auth;
+mod config;
+mod daemon;
+mod format;
+mod leap;
 mod ntp;
-
-use std::{error::Error, fmt::Display, str::FromStr};
-
-use chrono::{DateTime, Local, LocalResult, TimeZone, Utc};
-
-use clap::{Arg, ArgAction, Command};
+mod packet;
+mod report;
+mod roughtime;
+mod select;
+
+use std::{
+    error::Error,
+    fmt::Display,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+
+use auth::Keys;
+use clap::{
+    builder::{PossibleValuesParser, TypedValueParser},
+    parser::ValueSource,
+    Arg, ArgAction, ArgMatches, Command,
+};
 use color_eyre::{eyre::Context, Report};
-use tracing::{info, instrument};
+use config::{ConfigFile, Server, Settings, DEFAULT_SERVERS};
+use format::{Format, Zone, FORMATS};
+use leap::{LeapError, LeapTable, DEFAULT_LEAP_FILE};
+use packet::LeapIndicator;
+use report::{Correction, Status, SyncReport, DEFAULT_STATE_FILE};
+use tracing::{info, instrument, warn};
 use tracing_subscriber::{filter::Targets, layer::SubscriberExt, util::SubscriberInitExt};
 
 #[derive(Debug)]
-enum ClockError {
+pub enum ClockError {
     ChronoParse(chrono::ParseError),
     TimestampParse(std::num::ParseIntError),
     Libc(std::io::Error),
@@ -19,11 +45,25 @@ enum ClockError {
 
 impl Display for ClockError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{self:?}")
+        match self {
+            ClockError::ChronoParse(err) => write!(f, "unable to parse datetime: {err}"),
+            ClockError::TimestampParse(err) => write!(f, "unable to parse timestamp: {err}"),
+            ClockError::Libc(err) => write!(f, "{err}"),
+            ClockError::Custom(msg) => write!(f, "{msg}"),
+        }
     }
 }
 
-impl Error for ClockError {}
+impl Error for ClockError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ClockError::ChronoParse(err) => Some(err),
+            ClockError::TimestampParse(err) => Some(err),
+            ClockError::Libc(err) => Some(err),
+            ClockError::Custom(_) => None,
+        }
+    }
+}
 
 impl From<chrono::ParseError> for ClockError {
     fn from(value: chrono::ParseError) -> Self {
@@ -43,47 +83,25 @@ impl From<std::io::Error> for ClockError {
     }
 }
 
+/// Fastest rate adjtime(3) slews the clock at on Linux and the BSDs
+const SLEW_RATE_PPM: f64 = 500.0;
+
 struct Clock;
 
 impl Clock {
     #[instrument]
-    fn get(format: &str) -> String {
-        match format {
-            "timestamp" => Local::now().timestamp_millis().to_string(),
-            "rfc2822" => Local::now().to_rfc2822(),
-            "rfc3339" => Local::now().to_rfc3339(),
-            _ => unreachable!(),
-        }
-    }
-
-    fn from_str(format: &str, datetime: &str) -> Result<DateTime<Local>, Report> {
-        match format {
-            "timestamp" => {
-                let millis: i64 = datetime.parse()?;
-                match Local.timestamp_millis_opt(millis) {
-                    LocalResult::Single(dt) => Ok(dt.with_timezone(&Local)),
-                    _ => Err(Report::new(ClockError::Custom(
-                        "Incorrect timestamp_millis".to_string(),
-                    ))),
-                }
-            }
-            "rfc2822" => Ok(DateTime::parse_from_rfc2822(datetime)?.with_timezone(&Local)),
-            "rfc3339" => Ok(DateTime::parse_from_rfc3339(datetime)?.with_timezone(&Local)),
-            _ => unreachable!(),
-        }
+    fn get(format: &Format, zone: Zone) -> String {
+        format::format(Utc::now(), format, zone)
     }
 
     // see: https://linux.die.net/man/2/settimeofday
     #[cfg(unix)]
     #[instrument]
-    fn set(format: &str, datetime: &str, dry_run: bool) -> Result<(), Report> {
+    fn set(dt: DateTime<Utc>, dry_run: bool) -> Result<(), Report> {
         use std::mem::zeroed;
 
         use libc::{settimeofday, suseconds_t, time_t, timeval, timezone};
 
-        let dt = Clock::from_str(format, datetime)?;
-        // convert input datetime's timezone with local timezone to avoid confusing
-        // let dt = dt.with_timezone(&Local);
         info!("set time with: {:?}", dt);
 
         if dry_run {
@@ -93,7 +111,8 @@ impl Clock {
         // UNSAFE: init the timeval struct with zeroed
         let mut tv: timeval = unsafe { zeroed() };
         tv.tv_sec = dt.timestamp() as time_t;
-        tv.tv_usec = dt.timestamp_subsec_micros() as suseconds_t;
+        // POSIX time has no 23:59:60, it repeats 23:59:59 like the kernel does
+        tv.tv_usec = (dt.timestamp_subsec_micros() % 1_000_000) as suseconds_t;
 
         let mock_tz: *const timezone = std::ptr::null();
 
@@ -104,16 +123,159 @@ impl Clock {
         }
     }
 
+    // see: https://man7.org/linux/man-pages/man3/adjtime.3.html
+    #[cfg(unix)]
+    #[instrument]
+    fn slew(offset: chrono::Duration, dry_run: bool) -> Result<(), Report> {
+        use std::mem::zeroed;
+
+        use libc::{adjtime, suseconds_t, time_t, timeval};
+
+        info!("slew time by: {}", offset);
+
+        if dry_run {
+            return Ok(());
+        }
+
+        let micros = offset
+            .num_microseconds()
+            .ok_or_else(|| ClockError::Custom("offset too large to slew".to_string()))?;
+
+        // UNSAFE: init the timeval structs with zeroed
+        let mut delta: timeval = unsafe { zeroed() };
+        delta.tv_sec = micros.div_euclid(1_000_000) as time_t;
+        delta.tv_usec = micros.rem_euclid(1_000_000) as suseconds_t;
+        let mut unfinished: timeval = unsafe { zeroed() };
+
+        // UNSAFE: start the adjustment, replacing any still in progress
+        match unsafe { adjtime(&delta as *const timeval, &mut unfinished as *mut timeval) } {
+            0 => {
+                let left = unfinished.tv_sec as i64 * 1_000_000 + unfinished.tv_usec as i64;
+                if left != 0 {
+                    info!("replaced unfinished adjustment of {}us", left);
+                }
+                Ok(())
+            }
+            _ => Err(std::io::Error::last_os_error().into()),
+        }
+    }
+
+    /// Speed the clock up by `ppm`, or slow it down when negative
+    // see: https://man7.org/linux/man-pages/man2/adjtimex.2.html
+    #[cfg(target_os = "linux")]
+    #[instrument]
+    fn set_frequency(ppm: f64, dry_run: bool) -> Result<(), Report> {
+        use std::mem::zeroed;
+
+        use libc::{adjtimex, timex, ADJ_FREQUENCY};
+
+        info!("set frequency to: {:.3}ppm", ppm);
+
+        if dry_run {
+            return Ok(());
+        }
+
+        // UNSAFE: init the timex struct with zeroed
+        let mut tx: timex = unsafe { zeroed() };
+        tx.modes = ADJ_FREQUENCY;
+        // the kernel takes ppm with a 16 bit fraction
+        tx.freq = (ppm * 65536.0).round() as _;
+
+        // UNSAFE: only the frequency is changed as only it is in modes
+        match unsafe { adjtimex(&mut tx as *mut timex) } {
+            -1 => Err(std::io::Error::last_os_error().into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Have the kernel insert or delete a second at the next midnight UTC,
+    /// or withdraw a leap once the servers no longer announce it. Only
+    /// a leap `armed` by an earlier run, as recorded in the state file, is
+    /// withdrawn, one armed by the operator or another daemon is left alone.
+    /// Returns whether the kernel now holds a leap armed here.
+    // see: https://man7.org/linux/man-pages/man2/adjtimex.2.html
+    #[cfg(target_os = "linux")]
+    #[instrument]
+    fn announce_leap(leap: LeapIndicator, armed: bool, dry_run: bool) -> Result<bool, Report> {
+        use std::mem::zeroed;
+
+        use libc::{adjtimex, timex, ADJ_STATUS, STA_DEL, STA_INS};
+
+        let announced = matches!(leap, LeapIndicator::AddSecond | LeapIndicator::DeleteSecond);
+        if dry_run {
+            return Ok(armed);
+        }
+        if !(announced || armed) {
+            return Ok(false);
+        }
+
+        // UNSAFE: init the timex struct with zeroed, no modes only reads
+        let mut tx: timex = unsafe { zeroed() };
+        if unsafe { adjtimex(&mut tx as *mut timex) } == -1 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let status = match leap {
+            LeapIndicator::AddSecond => (tx.status & !STA_DEL) | STA_INS,
+            LeapIndicator::DeleteSecond => (tx.status & !STA_INS) | STA_DEL,
+            _ => tx.status & !(STA_INS | STA_DEL),
+        };
+        if status == tx.status {
+            // a leap already in place stays whoever armed it
+            return Ok(announced && armed);
+        }
+        info!("announce leap second: {:?}", leap);
+        tx.modes = ADJ_STATUS as _;
+        tx.status = status;
+        // UNSAFE: only the status is changed as only it is in modes
+        match unsafe { adjtimex(&mut tx as *mut timex) } {
+            -1 => Err(std::io::Error::last_os_error().into()),
+            _ => Ok(announced),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    #[instrument]
+    fn announce_leap(leap: LeapIndicator, _armed: bool, dry_run: bool) -> Result<bool, Report> {
+        if matches!(leap, LeapIndicator::AddSecond | LeapIndicator::DeleteSecond) && !dry_run {
+            tracing::warn!(
+                "leap second {:?} left to the system, it needs adjtimex(2)",
+                leap
+            );
+        }
+        Ok(false)
+    }
+
+    /// Only offsets are corrected here, the estimate is still reported
+    #[cfg(not(target_os = "linux"))]
+    #[instrument]
+    fn set_frequency(ppm: f64, dry_run: bool) -> Result<(), Report> {
+        info!("set frequency to: {:.3}ppm", ppm);
+        if ppm != 0.0 && !dry_run {
+            info!("frequency correction skipped, it needs adjtimex(2)");
+        }
+        Ok(())
+    }
+
     #[cfg(windows)]
     #[instrument]
-    fn set(_format: &str, _datetime: &str, dry_run: bool) -> Result<(), Report> {
-        use chrono::{Datelike, Timelike};
+    fn slew(offset: chrono::Duration, dry_run: bool) -> Result<(), Report> {
+        info!("slew time by: {}", offset);
+        if dry_run {
+            return Ok(());
+        }
+        Err(ClockError::Custom("slewing is not supported on windows".to_string()).into())
+    }
+
+    #[cfg(windows)]
+    #[instrument]
+    fn set(dt: DateTime<Utc>, dry_run: bool) -> Result<(), Report> {
+        use chrono::{Datelike, Local, Timelike};
         use std::mem::zeroed;
 
         use winapi::shared::minwindef::WORD;
         use winapi::um::{minwinbase::SYSTEMTIME, sysinfoapi::SetLocalTime};
 
-        let dt = Clock::from_str(_format, _datetime)?;
+        let dt = dt.with_timezone(&Local);
         info!("set time with: {:?}", dt);
         if dry_run {
             return Ok(());
@@ -154,13 +316,241 @@ impl Clock {
     }
 }
 
+/// Keys file named with `--keys`, or else in the config file
+fn load_keys(path: Option<&PathBuf>) -> Result<Keys, Report> {
+    match path {
+        Some(path) => {
+            Keys::load(path).wrap_err_with(|| format!("unable to read keys {}", path.display()))
+        }
+        None => Ok(Keys::default()),
+    }
+}
+
+/// Servers named with `--server`, or else in the config file, or else the
+/// defaults, with settings from the flags, the config file and the defaults
+/// in that order, and the keys authenticating them
+fn ntp_servers(matches: &ArgMatches) -> Result<(Vec<Server>, Keys), Report> {
+    let file = match matches.get_one::<PathBuf>("config") {
+        Some(path) => ConfigFile::load(path)
+            .wrap_err_with(|| format!("unable to read config {}", path.display()))?,
+        None => ConfigFile::default(),
+    };
+
+    let mut settings = file.settings(Settings::default());
+    if let Some(&port) = matches.get_one::<u16>("port") {
+        settings.port = port;
+    }
+    if let Some(&timeout) = matches.get_one::<u64>("timeout") {
+        settings.timeout = Duration::from_millis(timeout);
+    }
+    if let Some(&retries) = matches.get_one::<u32>("retries") {
+        settings.retries = retries;
+    }
+    if let Some(&key) = matches.get_one::<u32>("key") {
+        settings.key = Some(key);
+    }
+
+    let servers = match matches.get_many::<String>("server") {
+        Some(specs) => specs
+            .map(|spec| Server::parse(spec, settings))
+            .collect::<Result<_, _>>()?,
+        None => match file.servers(settings) {
+            servers if servers.is_empty() => DEFAULT_SERVERS
+                .iter()
+                .map(|host| Server::new(host, settings))
+                .collect(),
+            servers => servers,
+        },
+    };
+    let keys = load_keys(matches.get_one::<PathBuf>("keys").or(file.keys.as_ref()))?;
+    Ok((servers, keys))
+}
+
+/// Step or, with `slew`, slew the clock by `offset` milliseconds. Offsets
+/// above `threshold` are always stepped.
+fn correct(offset: f64, slew: bool, threshold: f64, dry_run: bool) -> Result<Correction, Report> {
+    // see: https://github.com/rust-in-action/code/issues/86
+    // let offset = offset.signum() * offset.abs().min(200) / 5;
+    let adjust = chrono::Duration::microseconds((offset * 1000.0).round() as i64);
+    info!("adjust: {}", adjust);
+
+    if slew && offset.abs() <= threshold {
+        Clock::slew(adjust, dry_run).wrap_err("unable to slew the clock")?;
+        Ok(Correction::Slew {
+            offset_ms: offset,
+            duration_s: offset.abs() / 1000.0 / (SLEW_RATE_PPM / 1e6),
+        })
+    } else {
+        let now = Utc::now() + adjust;
+        Clock::set(now, dry_run).wrap_err("unable to set the clock")?;
+        Ok(Correction::Step {
+            offset_ms: offset,
+            to: now,
+            threshold_ms: slew.then_some(threshold),
+        })
+    }
+}
+
+/// Pass on the leap second of `report` to the kernel, recording whether
+/// it is armed so a later run can withdraw it
+fn honour_leap(report: &mut SyncReport, dry_run: bool) -> Result<(), Report> {
+    report.leap_armed = Clock::announce_leap(report.leap, report.leap_armed, dry_run)
+        .wrap_err("unable to announce the leap second")?;
+    Ok(())
+}
+
+/// Whether the last run left a leap second armed in the kernel
+fn leap_armed(state: &Path) -> bool {
+    SyncReport::load(state).is_ok_and(|report| report.leap_armed)
+}
+
+/// What a dry run reports for a leap second, the JSON report has it as `leap`
+fn print_dry_run(correction: &Correction, leap: LeapIndicator) {
+    println!("would {correction}");
+    match leap {
+        LeapIndicator::AddSecond => println!("would insert a leap second at midnight UTC"),
+        LeapIndicator::DeleteSecond => println!("would delete a leap second at midnight UTC"),
+        _ => {}
+    }
+}
+
+/// The leap seconds list named by `--leap-file`, if the default one is missing
+/// there is none
+fn load_leaps(matches: &ArgMatches) -> Result<Option<LeapTable>, Report> {
+    let path = matches.get_one::<PathBuf>("leap file").unwrap();
+    match LeapTable::load(path) {
+        Ok(table) => Ok(Some(table)),
+        // not every system has tzdata
+        Err(LeapError::Io(err))
+            if err.kind() == std::io::ErrorKind::NotFound
+                && matches.value_source("leap file") == Some(ValueSource::DefaultValue) =>
+        {
+            Ok(None)
+        }
+        Err(err) => Err(err)
+            .wrap_err_with(|| format!("unable to load leap seconds from {}", path.display())),
+    }
+}
+
+/// `load_leaps` for the conversions that cannot do without
+fn leap_table(matches: &ArgMatches) -> Result<LeapTable, Report> {
+    load_leaps(matches)?.ok_or_else(|| {
+        ClockError::Custom(format!("no leap seconds list at {DEFAULT_LEAP_FILE}")).into()
+    })
+}
+
+fn tai_arg() -> Arg {
+    Arg::new("tai")
+        .long("tai")
+        .action(ArgAction::SetTrue)
+        .help("TAI instead of UTC, written like UTC and ignoring time zones")
+}
+
+fn leap_file_arg() -> Arg {
+    Arg::new("leap file")
+        .long("leap-file")
+        .value_name("FILE")
+        .value_parser(clap::value_parser!(PathBuf))
+        .default_value(DEFAULT_LEAP_FILE)
+        .help("IETF leap-seconds.list announcing leap seconds")
+}
+
+/// Record `report` for `status`, a sync is not undone by failing to
+fn save_state(report: &SyncReport, path: &Path) {
+    if let Err(err) = report.save(path) {
+        warn!("unable to write state file {}: {err}", path.display());
+    }
+}
+
+fn state_arg() -> Arg {
+    Arg::new("state")
+        .long("state")
+        .value_name("FILE")
+        .value_parser(clap::value_parser!(PathBuf))
+        .default_value(DEFAULT_STATE_FILE)
+        .help("file recording the last sync")
+}
+
+fn output_arg() -> Arg {
+    Arg::new("output")
+        .long("output")
+        .short('o')
+        .value_name("FORMAT")
+        .value_parser(["text", "json"])
+        .default_value("text")
+        .help("print a report for people or for monitoring")
+}
+
+/// Arguments of the subcommands that query NTP servers and correct the clock
+fn query_args() -> Vec<Arg> {
+    vec![
+        Arg::new("server")
+            .long("server")
+            .short('s')
+            .value_name("HOST[:PORT]")
+            .action(ArgAction::Append)
+            .help("server to query, repeat for more, replaces the configured ones"),
+        Arg::new("config")
+            .long("config")
+            .short('c')
+            .value_name("FILE")
+            .value_parser(clap::value_parser!(PathBuf))
+            .help("TOML file with the servers and how to query them"),
+        Arg::new("port")
+            .long("port")
+            .value_name("PORT")
+            .short('p')
+            .value_parser(clap::value_parser!(u16))
+            .help("port of servers that do not name one [default: 123]"),
+        Arg::new("timeout")
+            .long("timeout")
+            .value_name("MS")
+            .value_parser(clap::value_parser!(u64))
+            .help("milliseconds to wait for each response [default: 1000]"),
+        Arg::new("retries")
+            .long("retries")
+            .value_name("N")
+            .value_parser(clap::value_parser!(u32))
+            .help("times to query a server again after a failure [default: 0]"),
+        Arg::new("keys")
+            .long("keys")
+            .value_name("FILE")
+            .value_parser(clap::value_parser!(PathBuf))
+            .help("keys file with the keys shared with the servers"),
+        Arg::new("key")
+            .long("key")
+            .value_name("ID")
+            .value_parser(clap::value_parser!(u32).range(1..))
+            .help("ID of the key authenticating servers that do not name one"),
+        Arg::new("min survivors")
+            .long("min-survivors")
+            .value_name("N")
+            .value_parser(clap::value_parser!(usize))
+            .default_value("1")
+            .help("servers that must agree before the clock is set"),
+        Arg::new("step threshold")
+            .long("step-threshold")
+            .value_name("MS")
+            .value_parser(clap::value_parser!(f64))
+            .default_value("128")
+            .help("offset above which the clock is stepped rather than slewed"),
+        Arg::new("dry run")
+            .long("dry-run")
+            .short('d')
+            .action(ArgAction::SetTrue)
+            .help("report the correction without applying it"),
+        state_arg(),
+    ]
+}
+
 #[instrument]
 fn main() -> Result<(), Report> {
     color_eyre::install()?;
 
     let filter_layer =
         Targets::from_str(std::env::var("RUST_LOG").as_deref().unwrap_or("info")).unwrap();
-    let format_layer = tracing_subscriber::fmt::layer();
+    // logs stay out of the way of reports on stdout
+    let format_layer = tracing_subscriber::fmt::layer().with_writer(std::io::stderr);
     tracing_subscriber::registry()
         .with(filter_layer)
         .with(format_layer)
@@ -174,23 +564,59 @@ fn main() -> Result<(), Report> {
             Arg::new("format")
                 .long("format")
                 .short('f')
-                .value_parser(["timestamp", "rfc2822", "rfc3339"])
+                .value_parser(
+                    PossibleValuesParser::new(FORMATS).map(|name| name.parse::<Format>().unwrap()),
+                )
                 .help("input/output datetime format")
                 .long_help(
-                    "timestamp: the number of non-leap milliseconds since January 1, 1970 0:00:00 UTC
+                    "auto: guess the format of input, rfc3339 for output, the default for set
+unix: the number of non-leap seconds since January 1, 1970 0:00:00 UTC
+timestamp: the number of non-leap milliseconds since January 1, 1970 0:00:00 UTC
+micros: the number of non-leap microseconds since January 1, 1970 0:00:00 UTC
+nanos: the number of non-leap nanoseconds since January 1, 1970 0:00:00 UTC
+iso-week: ISO 8601 week date and time string such as 2003-W27-2T10:52:37+02:00
 rfc2822: RFC 2822 date and time string such as Tue, 1 Jul 2003 10:52:37 +0200
-rfc3339: FC 3339 and ISO 8601 date and time string such as 1996-12-19T16:39:57-08:00"
+rfc3339: FC 3339 and ISO 8601 date and time string such as 1996-12-19T16:39:57-08:00",
                 )
                 .default_value("rfc3339"),
         )
+        .arg(
+            Arg::new("strftime")
+                .long("strftime")
+                .value_name("PATTERN")
+                .value_parser(Format::strftime)
+                .conflicts_with("format")
+                .help("input/output datetime pattern such as \"%Y-%m-%d %H:%M\""),
+        )
+        .arg(
+            Arg::new("tz")
+                .long("tz")
+                .value_name("ZONE")
+                .value_parser(|name: &str| name.parse::<chrono_tz::Tz>())
+                .help(
+                    "IANA time zone such as Europe/Berlin, for output and input without an offset",
+                ),
+        )
+        .arg(
+            Arg::new("utc")
+                .long("utc")
+                .short('u')
+                .action(ArgAction::SetTrue)
+                .conflicts_with("tz")
+                .help("use UTC instead of the local time zone"),
+        )
         .subcommand(
             Command::new("get")
-                .about("get local time with corresponding format and local timezone"),
+                .about("get local time with corresponding format and timezone")
+                .arg(tai_arg())
+                .arg(leap_file_arg()),
         )
         .subcommand(
             Command::new("set")
                 .about("Set local time with corresponding format")
                 .arg(Arg::new("datetime").required(true))
+                .arg(tai_arg())
+                .arg(leap_file_arg())
                 .arg(
                     Arg::new("dry run")
                         .long("dry-run")
@@ -198,40 +624,310 @@ rfc3339: FC 3339 and ISO 8601 date and time string such as 1996-12-19T16:39:57-0
                         .action(ArgAction::SetTrue),
                 ),
         )
+        .subcommand(
+            Command::new("serve")
+                .about("Answer NTP client requests with the local time, ignore format")
+                .arg(
+                    Arg::new("port")
+                        .long("port")
+                        .short('p')
+                        .value_parser(clap::value_parser!(u16))
+                        .default_value("123")
+                        .help("UDP port to listen on"),
+                )
+                .arg(
+                    Arg::new("listen")
+                        .long("listen")
+                        .value_name("ADDRESS")
+                        .action(ArgAction::Append)
+                        .value_parser(clap::value_parser!(IpAddr))
+                        .default_value("::")
+                        .help("address to listen on, repeat for more, :: takes IPv4 too on Linux"),
+                )
+                .arg(
+                    Arg::new("stratum")
+                        .long("stratum")
+                        .value_parser(clap::value_parser!(u8).range(1..16))
+                        .default_value("10")
+                        .help("stratum to report, the local clock is not a primary reference"),
+                )
+                .arg(
+                    Arg::new("keys")
+                        .long("keys")
+                        .value_name("FILE")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("keys file to authenticate requests with"),
+                )
+                .arg(leap_file_arg()),
+        )
         .subcommand(
             Command::new("ntp")
                 .about("Set local time from ntp, ignore format")
+                .arg(
+                    Arg::new("verbose")
+                        .long("verbose")
+                        .short('v')
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("output")
+                        .help("print every field of each server's response"),
+                )
+                .arg(
+                    Arg::new("slew")
+                        .long("slew")
+                        .action(ArgAction::SetTrue)
+                        .help("adjust the clock gradually instead of stepping it"),
+                )
+                .arg(output_arg())
+                .args(query_args()),
+        )
+        .subcommand(
+            Command::new("daemon")
+                .about("Keep local time in sync with ntp, ignore format")
+                .arg(
+                    Arg::new("drift file")
+                        .long("drift-file")
+                        .value_name("FILE")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("file keeping the frequency correction across restarts"),
+                )
+                .arg(
+                    Arg::new("min poll")
+                        .long("min-poll")
+                        .value_name("LOG2_SECONDS")
+                        .value_parser(clap::value_parser!(u8).range(4..18))
+                        .default_value("6")
+                        .help("shortest poll interval as a power of two seconds"),
+                )
+                .arg(
+                    Arg::new("max poll")
+                        .long("max-poll")
+                        .value_name("LOG2_SECONDS")
+                        .value_parser(clap::value_parser!(u8).range(4..18))
+                        .default_value("10")
+                        .help("longest poll interval as a power of two seconds"),
+                )
+                .args(query_args()),
+        )
+        .subcommand(
+            Command::new("roughtime")
+                .about("Set local time from an authenticated Roughtime server, ignore format")
+                .arg(
+                    Arg::new("server")
+                        .long("server")
+                        .short('s')
+                        .value_name("HOST[:PORT]")
+                        .required(true)
+                        .help("server to query, on port 2002 unless named"),
+                )
+                .arg(
+                    Arg::new("public key")
+                        .long("public-key")
+                        .short('k')
+                        .value_name("KEY")
+                        .value_parser(roughtime::parse_public_key)
+                        .required(true)
+                        .help("long-term Ed25519 public key of the server, in base64 or hex"),
+                )
+                .arg(
+                    Arg::new("timeout")
+                        .long("timeout")
+                        .value_name("MS")
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("1000")
+                        .help("milliseconds to wait for the response"),
+                )
+                .arg(
+                    Arg::new("retries")
+                        .long("retries")
+                        .value_name("N")
+                        .value_parser(clap::value_parser!(u32))
+                        .default_value("0")
+                        .help("times to query the server again after a failure"),
+                )
                 .arg(
                     Arg::new("dry run")
                         .long("dry-run")
                         .short('d')
-                        .action(ArgAction::SetTrue),
+                        .action(ArgAction::SetTrue)
+                        .help("report the correction without applying it"),
                 ),
         )
+        .subcommand(
+            Command::new("status")
+                .about("Show the last sync of ntp or daemon, like chronyc tracking")
+                .arg(output_arg())
+                .arg(state_arg())
+                .arg(leap_file_arg()),
+        )
         .get_matches();
 
-    let format = matches.get_one::<String>("format").unwrap();
+    let format = match matches.get_one::<Format>("strftime") {
+        Some(pattern) => pattern.clone(),
+        None => matches.get_one::<Format>("format").unwrap().clone(),
+    };
+    let zone = match matches.get_one::<chrono_tz::Tz>("tz") {
+        Some(&tz) => Zone::Named(tz),
+        None if matches.get_flag("utc") => Zone::Utc,
+        None => Zone::Local,
+    };
 
     match matches.subcommand() {
         Some(("set", set_matches)) => {
             let datetime = set_matches.get_one::<String>("datetime").unwrap();
             let dry_run = set_matches.get_flag("dry run");
-            Clock::set(format, datetime, dry_run).wrap_err("unable to set the clock")?;
+            // guess unless told otherwise
+            let format = match matches.value_source("format") {
+                Some(ValueSource::DefaultValue) if format == Format::Rfc3339 => Format::Auto,
+                _ => format,
+            };
+            let dt = match set_matches.get_flag("tai") {
+                true => {
+                    let tai = format::parse(datetime, &format, Zone::Utc)
+                        .wrap_err_with(|| format!("unable to parse {datetime:?}"))?;
+                    leap_table(set_matches)?
+                        .tai_to_utc(tai.naive_utc())
+                        .ok_or_else(|| ClockError::Custom(format!("{tai} is before 1972")))?
+                }
+                false => format::parse(datetime, &format, zone)
+                    .wrap_err_with(|| format!("unable to parse {datetime:?}"))?,
+            };
+            Clock::set(dt, dry_run).wrap_err("unable to set the clock")?;
         }
         Some(("ntp", ntp_matches)) => {
-            let offset = ntp::check_time()? as isize;
-
-            // see: https://github.com/rust-in-action/code/issues/86
-            // let offset = offset.signum() * offset.abs().min(200) / 5;
-            let adjust_ms = chrono::Duration::milliseconds(offset as i64);
-            info!("adjust ms: {}", adjust_ms);
+            let verbose = ntp_matches.get_flag("verbose");
+            let (servers, keys) = ntp_servers(ntp_matches)?;
+            let min_survivors = *ntp_matches.get_one::<usize>("min survivors").unwrap();
+            let (servers, offset) = ntp::check_time(&servers, &keys, min_survivors, verbose);
 
-            let now = (Utc::now() + adjust_ms).to_rfc3339();
+            let slew = ntp_matches.get_flag("slew");
+            let threshold = *ntp_matches.get_one::<f64>("step threshold").unwrap();
             let dry_run = ntp_matches.get_flag("dry run");
-            Clock::set("rfc3339", &now, dry_run).wrap_err("unable to set the clock")?;
+            let state = ntp_matches.get_one::<PathBuf>("state").unwrap();
+            let mut report = SyncReport::new(servers, dry_run);
+            report.leap_armed = leap_armed(state);
+            report.offset_ms = offset.as_ref().ok().copied();
+            let result = offset
+                .map_err(Report::new)
+                .and_then(|offset| correct(offset, slew, threshold, dry_run));
+            report.corrected(&result);
+            if result.is_ok() {
+                honour_leap(&mut report, dry_run)?;
+            }
+
+            save_state(&report, state);
+            match ntp_matches.get_one::<String>("output").unwrap().as_str() {
+                "json" => println!("{}", report.to_json()),
+                _ => match &report.correction {
+                    Some(correction) if dry_run => print_dry_run(correction, report.leap),
+                    _ => {}
+                },
+            }
+            result?;
+        }
+        Some(("roughtime", roughtime_matches)) => {
+            let settings = Settings {
+                port: roughtime::DEFAULT_PORT,
+                timeout: Duration::from_millis(
+                    *roughtime_matches.get_one::<u64>("timeout").unwrap(),
+                ),
+                retries: *roughtime_matches.get_one::<u32>("retries").unwrap(),
+                key: None,
+            };
+            let spec = roughtime_matches.get_one::<String>("server").unwrap();
+            let server = Server::parse(spec, settings)?;
+            let public_key = roughtime_matches
+                .get_one::<ed25519_dalek::VerifyingKey>("public key")
+                .unwrap();
+            let dry_run = roughtime_matches.get_flag("dry run");
+
+            let result = roughtime::query(&server, public_key)
+                .wrap_err_with(|| format!("unable to get the time from {server}"))?;
+            let (offset, uncertainty) = (result.offset(), result.uncertainty());
+            info!(
+                "{server} => {} ±{}ms, {offset:+.3}ms away from local system time",
+                result.time.midpoint.to_rfc3339(),
+                result.time.radius.num_milliseconds()
+            );
+            // the server vouches for no more than that, a step would not help
+            if offset.abs() <= uncertainty {
+                println!("the clock is within the {uncertainty:.0}ms uncertainty of {server}");
+            } else {
+                let correction = correct(offset, false, 0.0, dry_run)?;
+                if dry_run {
+                    println!("would {correction}");
+                }
+            }
+        }
+        Some(("status", status_matches)) => {
+            let status = Status {
+                report: SyncReport::load(status_matches.get_one::<PathBuf>("state").unwrap())?,
+                leap_seconds: load_leaps(status_matches)?.map(|table| table.status(Utc::now())),
+            };
+            match status_matches.get_one::<String>("output").unwrap().as_str() {
+                "json" => println!("{}", serde_json::to_string_pretty(&status)?),
+                _ => println!("{status}"),
+            }
+        }
+        Some(("daemon", daemon_matches)) => {
+            let (servers, keys) = ntp_servers(daemon_matches)?;
+            let options = daemon::Options {
+                servers,
+                keys,
+                min_survivors: *daemon_matches.get_one::<usize>("min survivors").unwrap(),
+                step_threshold: *daemon_matches.get_one::<f64>("step threshold").unwrap(),
+                drift_file: daemon_matches.get_one::<PathBuf>("drift file").cloned(),
+                state_file: daemon_matches.get_one::<PathBuf>("state").unwrap().clone(),
+                min_poll: *daemon_matches.get_one::<u8>("min poll").unwrap(),
+                max_poll: *daemon_matches.get_one::<u8>("max poll").unwrap(),
+                dry_run: daemon_matches.get_flag("dry run"),
+            };
+            if options.min_poll > options.max_poll {
+                return Err(Report::new(ClockError::Custom(
+                    "--min-poll is above --max-poll".to_string(),
+                )));
+            }
+            daemon::run(&options)?;
+        }
+        Some(("serve", serve_matches)) => {
+            let port = *serve_matches.get_one::<u16>("port").unwrap();
+            let stratum = *serve_matches.get_one::<u8>("stratum").unwrap();
+            let keys = load_keys(serve_matches.get_one::<PathBuf>("keys"))?;
+            let leaps = load_leaps(serve_matches)?;
+            if leaps
+                .as_ref()
+                .is_some_and(|table| table.is_expired(Utc::now()))
+            {
+                warn!("the leap seconds list has expired, leap seconds may go unannounced");
+            }
+            let addrs: Vec<IpAddr> = serve_matches
+                .get_many::<IpAddr>("listen")
+                .unwrap()
+                .copied()
+                .collect();
+            let sockets = ntp::listen(&addrs, port)
+                .wrap_err_with(|| format!("unable to listen on port {port}"))?;
+            // each address gets a thread, the first one to fail ends serving
+            let (failed, failures) = std::sync::mpsc::channel();
+            for socket in sockets {
+                info!("serving ntp on {}", socket.local_addr()?);
+                let (failed, keys, leaps) = (failed.clone(), keys.clone(), leaps.clone());
+                std::thread::spawn(move || {
+                    let _ = failed.send(ntp::serve(&socket, stratum, &keys, leaps.as_ref()));
+                });
+            }
+            drop(failed);
+            if let Ok(result) = failures.recv() {
+                result.wrap_err("unable to serve ntp")?;
+            }
+        }
+        Some(("get", get_matches)) if get_matches.get_flag("tai") => {
+            let tai = leap_table(get_matches)?
+                .utc_to_tai(Utc::now())
+                .ok_or_else(|| ClockError::Custom("the leap seconds list is empty".to_string()))?;
+            println!("{}", format::format(tai.and_utc(), &format, Zone::Utc));
         }
         Some(_) | None => {
-            let now = Clock::get(format);
+            let now = Clock::get(&format, zone);
             println!("{now}");
         }
     }
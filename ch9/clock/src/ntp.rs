@@ -1,15 +1,19 @@
-use std::{net::UdpSocket, time::Duration};
-
-use byteorder::{BigEndian, ReadBytesExt};
-use chrono::{DateTime, TimeZone, Utc};
-use tracing::info;
-
-// without authenticator
-const NTP_MESSAGE_LENGTH: usize = 48;
-const LOCAL_ADDR: &str = "0.0.0.0:1230";
-
-// see: https://stackoverflow.com/a/29138806
-const NTP_TO_UNIX_SECONDS: i64 = (70 * 365 + 17) * 86400;
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    thread,
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use tracing::{debug, info, warn};
+
+use crate::auth::{Authenticator, Key, Keys, MAX_MAC_LENGTH};
+use crate::config::Server;
+use crate::leap::LeapTable;
+use crate::packet::{Mode, NtpError, NtpPacket, NTP_MESSAGE_LENGTH};
+use crate::report::ServerReport;
+use crate::select::{self, Sample, TooFewSurvivors};
 
 #[derive(Debug)]
 struct NTPResult {
@@ -17,17 +21,7 @@ struct NTPResult {
     t2: DateTime<Utc>,
     t3: DateTime<Utc>,
     t4: DateTime<Utc>,
-}
-
-#[derive(Debug)]
-struct NTPMessage {
-    data: [u8; NTP_MESSAGE_LENGTH],
-}
-
-#[derive(Debug)]
-struct NTPTimestamp {
-    seconds: u32,
-    fraction: u32,
+    response: NtpPacket,
 }
 
 impl NTPResult {
@@ -40,140 +34,440 @@ impl NTPResult {
     fn delay(&self) -> i64 {
         ((self.t4 - self.t1) - (self.t3 - self.t2)).num_milliseconds()
     }
-}
 
-impl NTPMessage {
-    fn new() -> Self {
-        Self {
-            data: [0; NTP_MESSAGE_LENGTH],
-        }
+    /// Half the round trip to the server plus the server's own root distance
+    fn distance(&self) -> f64 {
+        let root =
+            self.response.root_delay_seconds() / 2.0 + self.response.root_dispersion_seconds();
+        self.delay() as f64 / 2.0 + root * 1000.0
     }
+}
 
-    fn client() -> Self {
-        const VN: u8 = 0b00_100_000; // version 4
-        const MODE: u8 = 0b011; // mode client
-
-        let mut msg = Self::new();
-        msg.data[0] |= VN;
-        msg.data[0] |= MODE;
-
-        msg
-    }
+/// Query the server at `addr`, authenticating both ways with `key` if given
+fn ntp_roundtrip(
+    addr: SocketAddr,
+    timeout: Duration,
+    key: Option<&Key>,
+) -> Result<NTPResult, NtpError> {
+    let mut request = NtpPacket::client();
+    let mut response = [0; NTP_MESSAGE_LENGTH + MAX_MAC_LENGTH];
+
+    // an ephemeral port of the same family, so queries never share one
+    let local: SocketAddr = match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let udp = UdpSocket::bind(local)?;
+    udp.connect(addr)?;
+    udp.set_read_timeout(Some(timeout))?;
 
-    fn parse_timestamp(&self, i: usize) -> Result<NTPTimestamp, std::io::Error> {
-        let mut reader = &self.data[i..i + 8];
-        let seconds = reader.read_u32::<BigEndian>()?;
-        let fraction = reader.read_u32::<BigEndian>()?;
+    let t1 = Utc::now();
+    // the server echoes it as the origin timestamp, tying the reply to this request
+    request.transmit_time = t1.into();
 
-        Ok(NTPTimestamp { seconds, fraction })
-    }
+    match key {
+        Some(key) => udp.send(&key.sign(&request.to_bytes()))?,
+        None => udp.send(&request.to_bytes())?,
+    };
+    let len = udp.recv(&mut response)?;
 
-    fn rx_time(&self) -> Result<NTPTimestamp, std::io::Error> {
-        self.parse_timestamp(32)
-    }
+    let t4 = Utc::now();
 
-    fn tx_time(&self) -> Result<NTPTimestamp, std::io::Error> {
-        self.parse_timestamp(40)
+    let response = &response[..len];
+    if let Some(key) = key {
+        key.verify_packet(response)?;
     }
+    let response = NtpPacket::parse(response)?;
+    response.validate_response(&request)?;
+
+    let t2 = response.receive_time.into();
+    let t3 = response.transmit_time.into();
+
+    Ok(NTPResult {
+        t1,
+        t2,
+        t3,
+        t4,
+        response,
+    })
 }
 
-impl From<NTPTimestamp> for DateTime<Utc> {
-    fn from(ntp: NTPTimestamp) -> Self {
-        let secs = ntp.seconds as i64 - NTP_TO_UNIX_SECONDS;
-        let mut nsecs = ntp.fraction as f64;
-
-        nsecs *= 1e9;
-        nsecs /= 2_f64.powi(32);
+/// `ntp_roundtrip` with the first of the IPv4 and IPv6 addresses of `server`
+/// that answers, sending the queries again after failures up to its retry
+/// count. Kiss-o'-death replies are not retried.
+fn query(server: &Server, keys: &Keys) -> Result<NTPResult, NtpError> {
+    let key = match server.key {
+        Some(id) => Some(keys.get(id).ok_or(NtpError::UnknownKey(id))?),
+        None => None,
+    };
+
+    let addrs: Vec<SocketAddr> = (server.host.as_str(), server.port)
+        .to_socket_addrs()?
+        .collect();
+    if addrs.is_empty() {
+        let err = io::Error::new(io::ErrorKind::NotFound, "no addresses found");
+        return Err(err.into());
+    }
 
-        Utc.timestamp_opt(secs, nsecs as u32).unwrap()
+    let mut attempt = 0;
+    loop {
+        let mut last_err = None;
+        for &addr in &addrs {
+            match ntp_roundtrip(addr, server.timeout, key) {
+                Err(NtpError::KissOfDeath(code)) => return Err(NtpError::KissOfDeath(code)),
+                Err(err) => {
+                    debug!("{server} => {addr} failed: {err}");
+                    last_err = Some(err);
+                }
+                result => return result,
+            }
+        }
+        let err = last_err.expect("at least one address was tried");
+        if attempt == server.retries {
+            return Err(err);
+        }
+        attempt += 1;
+        debug!("{server} => retry {attempt} after: {err}");
     }
 }
 
-fn ntp_roundtrip(host: &str, port: u16) -> Result<NTPResult, std::io::Error> {
-    let destination = format!("{}:{}", host, port);
-    let timeout = Duration::from_secs(1);
-
-    let request = NTPMessage::client();
-    let mut response = NTPMessage::new();
-
-    let message = request.data;
-
-    let udp = UdpSocket::bind(LOCAL_ADDR)?;
-    udp.connect(destination).expect("unable to connect");
-
-    let t1 = Utc::now();
-
-    udp.send(&message)?;
-    udp.set_read_timeout(Some(timeout))?;
-    udp.recv_from(&mut response.data)?;
+/// Where `serve` listens unless told otherwise, on Linux IPv4 clients
+/// reach it too as IPv4-mapped addresses
+pub const DEFAULT_LISTEN: IpAddr = IpAddr::V6(Ipv6Addr::UNSPECIFIED);
 
-    let t4 = Utc::now();
-
-    let t2 = response.rx_time()?.into();
-
-    let t3 = response.tx_time()?.into();
-
-    Ok(NTPResult { t1, t2, t3, t4 })
-}
-
-// see: https://en.wikipedia.org/wiki/Weighted_arithmetic_mean
-fn weighted_mean(offsets: &[f64], offset_weights: &[f64]) -> f64 {
-    offsets
+/// A socket on `port` of each of `addrs` for `serve`
+pub fn listen(addrs: &[IpAddr], port: u16) -> Result<Vec<UdpSocket>, std::io::Error> {
+    addrs
         .iter()
-        .zip(offset_weights)
-        .fold(0f64, |s, (x, w)| s + x * w)
-        / offset_weights.iter().sum::<f64>()
+        .map(|&addr| match UdpSocket::bind((addr, port)) {
+            // a system without IPv6 still answers over IPv4
+            Err(err) if addr == DEFAULT_LISTEN => {
+                debug!("unable to listen on {addr}, using IPv4 only: {err}");
+                UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))
+            }
+            result => result,
+        })
+        .collect()
 }
 
-pub fn check_time() -> Result<f64, std::io::Error> {
-    const NTP_PORT: u16 = 123;
-
-    let servers = [
-        "time.nist.gov",
-        "ntp1.aliyun.com",
-        // "time.windows.com",
-        "time.apple.com",
-        "time.google.com",
-        "cn.ntp.org.cn",
-        "ntp.ntsc.ac.cn",
-        "ntp.tuna.tsinghua.edu.cn",
-    ];
-
-    let mut times = Vec::with_capacity(servers.len());
+/// Answer client requests on `socket` with the local clock, reporting
+/// `stratum`, until receiving fails. A reply that cannot be sent only
+/// loses that client. Requests with a MAC made
+/// with one of `keys` get a reply with a MAC, others a crypto-NAK. Leap
+/// seconds of `leaps` are announced on the day they happen.
+// see: https://datatracker.ietf.org/doc/html/rfc4330#section-5
+pub fn serve(
+    socket: &UdpSocket,
+    stratum: u8,
+    keys: &Keys,
+    leaps: Option<&LeapTable>,
+) -> Result<(), std::io::Error> {
+    let started = Utc::now();
+    let mut data = [0; NTP_MESSAGE_LENGTH + MAX_MAC_LENGTH];
+
+    loop {
+        let (len, peer) = socket.recv_from(&mut data)?;
+        let rx = Utc::now();
+
+        let request = match NtpPacket::parse(&data[..len]) {
+            Ok(request) if request.mode == Mode::Client => request,
+            Ok(request) => {
+                debug!("{peer} => ignored mode {:?}", request.mode);
+                continue;
+            }
+            Err(err) => {
+                debug!("{peer} => ignored: {err}");
+                continue;
+            }
+        };
+
+        let key = match Authenticator::read(&data[..len]) {
+            Ok(Authenticator::None) => None,
+            Ok(Authenticator::Mac { key_id, .. }) => match keys.get(key_id) {
+                Some(key) if key.verify_packet(&data[..len]).is_ok() => Some(key),
+                _ => {
+                    let response = NtpPacket::server(&request, stratum, started, rx);
+                    let nak = Authenticator::crypto_nak(&response.to_bytes());
+                    match socket.send_to(&nak, peer) {
+                        Ok(_) => debug!("{peer} => crypto-NAK for key {key_id}"),
+                        Err(err) => warn!("{peer} => unable to send crypto-NAK: {err}"),
+                    }
+                    continue;
+                }
+            },
+            Ok(Authenticator::CryptoNak) | Err(_) => {
+                debug!("{peer} => ignored invalid authenticator");
+                continue;
+            }
+        };
 
-    for &server in &servers {
-        let calc = ntp_roundtrip(server, NTP_PORT);
+        let mut response = NtpPacket::server(&request, stratum, started, rx);
+        if let Some(leaps) = leaps {
+            response.leap = leaps.leap_indicator(rx);
+        }
+        response.transmit_time = Utc::now().into();
+        let reply = match key {
+            Some(key) => key.sign(&response.to_bytes()),
+            None => response.to_bytes().to_vec(),
+        };
+        match socket.send_to(&reply, peer) {
+            Ok(_) => debug!("{peer} => answered"),
+            Err(err) => warn!("{peer} => unable to answer: {err}"),
+        }
+    }
+}
 
-        match calc {
+/// Offset of the local clock in milliseconds agreed on by at least
+/// `min_survivors` servers, along with what each server answered.
+/// `verbose` prints every field of each server's response. Servers with a
+/// key ID are authenticated with that key of `keys`.
+pub fn check_time(
+    servers: &[Server],
+    keys: &Keys,
+    min_survivors: usize,
+    verbose: bool,
+) -> (Vec<ServerReport>, Result<f64, TooFewSurvivors>) {
+    let mut samples = Vec::with_capacity(servers.len());
+    let mut reports = Vec::with_capacity(servers.len());
+
+    // every server gets its own thread, so waiting takes only the longest timeout
+    let results: Vec<_> = thread::scope(|scope| {
+        let queries: Vec<_> = servers
+            .iter()
+            .map(|server| scope.spawn(move || query(server, keys)))
+            .collect();
+        queries
+            .into_iter()
+            .map(|query| {
+                query
+                    .join()
+                    .unwrap_or_else(|err| std::panic::resume_unwind(err))
+            })
+            .collect()
+    });
+
+    for (server, result) in servers.iter().zip(results) {
+        let name = server.to_string();
+        match result {
             Ok(time) => {
                 info!(
                     "{} => {}ms away from local system time",
-                    server,
+                    name,
                     time.offset()
                 );
-                times.push(time);
+                if verbose {
+                    println!(
+                        "{name}\n{}\noffset: {}ms, delay: {}ms\n",
+                        time.response,
+                        time.offset(),
+                        time.delay()
+                    );
+                }
+                reports.push(ServerReport {
+                    server: name.clone(),
+                    reachable: true,
+                    stratum: Some(time.response.stratum),
+                    offset_ms: Some(time.offset() as f64),
+                    delay_ms: Some(time.delay() as f64),
+                    distance_ms: Some(time.distance()),
+                    leap: Some(time.response.leap),
+                    selected: false,
+                    error: None,
+                    rejected: None,
+                });
+                samples.push(Sample {
+                    name,
+                    offset: time.offset() as f64,
+                    distance: time.distance(),
+                });
             }
-            Err(_) => {
-                info!("{} => ? [response took too long]", server);
+            Err(err) => {
+                info!("{} => ? [{err}]", name);
+                if verbose {
+                    println!("{name}\nno usable response: {err}\n");
+                }
+                reports.push(ServerReport::unreachable(name, err.to_string()));
             }
         }
     }
 
-    let mut offsets = Vec::with_capacity(servers.len());
-    let mut offset_weights = Vec::with_capacity(servers.len());
+    let selection = select::select(samples);
+    for (sample, rejection) in &selection.rejected {
+        info!("{} => rejected, {rejection}", sample.name);
+        if verbose {
+            println!("{}\nrejected: {rejection}\n", sample.name);
+        }
+    }
+    for report in &mut reports {
+        report.selected = selection.survivors.iter().any(|s| s.name == report.server);
+        report.rejected = selection
+            .rejected
+            .iter()
+            .find(|(s, _)| s.name == report.server)
+            .map(|(_, rejection)| rejection.to_string());
+    }
+
+    let offset = match selection.offset() {
+        Some(offset) if selection.survivors.len() >= min_survivors => Ok(offset),
+        _ => Err(TooFewSurvivors {
+            survivors: selection.survivors.len(),
+            required: min_survivors,
+        }),
+    };
+    (reports, offset)
+}
+
+#[cfg(test)]
+mod ntp_test {
+    use std::sync::{Arc, Barrier};
+
+    use super::*;
+    use crate::packet::LeapIndicator;
+
+    #[test]
+    fn roundtrip_against_local_server() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = socket.local_addr().unwrap().port();
+        thread::spawn(move || serve(&socket, 10, &Keys::default(), None));
+
+        let addr = ([127, 0, 0, 1], port).into();
+        let result = ntp_roundtrip(addr, Duration::from_secs(1), None).unwrap();
+        assert!(result.offset().abs() <= 1, "offset {}ms", result.offset());
+        assert!((0..1000).contains(&result.delay()));
+        assert!(result.t1 <= result.t2 && result.t3 <= result.t4);
+        assert_eq!(result.response.stratum, 10);
+        assert_eq!(result.response.kiss_code(), "LOCL");
+    }
 
-    for time in times {
-        let offset = time.offset() as f64;
-        let delay = time.delay() as f64;
+    #[test]
+    fn retries_until_the_server_answers() {
+        // nothing listens on the port until after the first attempt
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let server = Server {
+            host: "127.0.0.1".to_string(),
+            port,
+            timeout: Duration::from_millis(200),
+            retries: 3,
+            key: None,
+        };
+        thread::spawn(move || {
+            let mut buf = [0; NTP_MESSAGE_LENGTH];
+            // drop the first request
+            socket.recv_from(&mut buf).unwrap();
+            serve(&socket, 10, &Keys::default(), None)
+        });
+
+        let (reports, offset) = check_time(&[server], &Keys::default(), 1, false);
+        let offset = offset.unwrap();
+        assert!(offset.abs() <= 1.0, "offset {offset}ms");
+        assert!(reports[0].reachable && reports[0].selected);
+        assert_eq!(reports[0].stratum, Some(10));
+    }
 
-        let weight = 1_000_000.0 / (delay * delay);
-        if weight.is_finite() {
-            offsets.push(offset);
-            offset_weights.push(weight);
+    /// Other systems keep IPv6 sockets from IPv4 clients by default
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn serves_both_families_by_default() {
+        let socket = listen(&[DEFAULT_LISTEN], 0).unwrap().remove(0);
+        let local = socket.local_addr().unwrap();
+        thread::spawn(move || serve(&socket, 10, &Keys::default(), None));
+
+        let mut hosts = vec![format!("127.0.0.1:{}", local.port())];
+        // without IPv6 the server falls back to IPv4
+        if local.is_ipv6() {
+            hosts.push(format!("[::1]:{}", local.port()));
         }
+        for host in hosts {
+            let server = Server::parse(&host, Default::default()).unwrap();
+            let result = query(&server, &Keys::default()).unwrap();
+            assert_eq!(result.response.stratum, 10, "{host}");
+        }
+    }
+
+    #[test]
+    fn queries_servers_concurrently() {
+        // servers that answer only once all of them were asked, so queries
+        // made one after the other time out waiting for the first answer
+        let barrier = Arc::new(Barrier::new(4));
+        let servers: Vec<_> = (0..4)
+            .map(|_| {
+                let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+                let port = socket.local_addr().unwrap().port();
+                let barrier = Arc::clone(&barrier);
+                thread::spawn(move || {
+                    let mut buf = [0; NTP_MESSAGE_LENGTH];
+                    socket.peek_from(&mut buf).unwrap();
+                    barrier.wait();
+                    serve(&socket, 10, &Keys::default(), None)
+                });
+                Server {
+                    host: "127.0.0.1".to_string(),
+                    port,
+                    timeout: Duration::from_secs(5),
+                    retries: 0,
+                    key: None,
+                }
+            })
+            .collect();
+
+        let (reports, offset) = check_time(&servers, &Keys::default(), 1, false);
+        assert!(offset.is_ok());
+        assert!(reports.iter().all(|r| r.reachable), "{reports:?}");
     }
 
-    let avg_offset = weighted_mean(&offsets, &offset_weights);
+    #[test]
+    fn announces_leap_seconds() {
+        // a leap second at the coming midnight
+        let midnight = Utc::now().date_naive().succ_opt().unwrap();
+        let ntp_seconds =
+            midnight.and_time(Default::default()).and_utc().timestamp() + 2_208_988_800;
+        let leaps = LeapTable::parse(&format!(
+            "#$ 3960835200\n#@ 4000000000\n3692217600 37\n{ntp_seconds} 38\n"
+        ))
+        .unwrap();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = socket.local_addr().unwrap().port();
+        thread::spawn(move || serve(&socket, 10, &Keys::default(), Some(&leaps)));
+
+        let server = Server::parse(&format!("127.0.0.1:{port}"), Default::default()).unwrap();
+        let (reports, _) = check_time(&[server], &Keys::default(), 1, false);
+        assert_eq!(reports[0].leap, Some(LeapIndicator::AddSecond));
+    }
 
-    Ok(avg_offset)
+    #[test]
+    fn authenticates_both_ways() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let server_keys =
+            Keys::parse("1 SHA1 secret\n2 AES128 HEX:000102030405060708090a0b0c0d0e0f").unwrap();
+        thread::spawn(move || serve(&socket, 10, &server_keys, None));
+
+        let keys = Keys::parse(
+            "1 SHA1 secret\n2 AES128 HEX:0f0e0d0c0b0a09080706050403020100\n3 MD5 other",
+        )
+        .unwrap();
+        let addr = ([127, 0, 0, 1], port).into();
+        let timeout = Duration::from_secs(1);
+
+        let result = ntp_roundtrip(addr, timeout, keys.get(1)).unwrap();
+        assert_eq!(result.response.stratum, 10);
+        // a key the server has with another secret, and one it does not have
+        for id in [2, 3] {
+            assert!(matches!(
+                ntp_roundtrip(addr, timeout, keys.get(id)),
+                Err(NtpError::CryptoNak)
+            ));
+        }
+
+        let server = Server {
+            key: Some(4),
+            ..Server::parse(&format!("127.0.0.1:{port}"), Default::default()).unwrap()
+        };
+        assert!(matches!(
+            query(&server, &keys),
+            Err(NtpError::UnknownKey(4))
+        ));
+    }
 }
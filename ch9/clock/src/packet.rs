@@ -0,0 +1,514 @@
+use std::{error::Error, fmt::Display, net::Ipv4Addr};
+
+use byteorder::{BigEndian, ReadBytesExt};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+// header without extension fields or authenticator
+pub const NTP_MESSAGE_LENGTH: usize = 48;
+
+// see: https://stackoverflow.com/a/29138806
+const NTP_TO_UNIX_SECONDS: i64 = (70 * 365 + 17) * 86400;
+// seconds in an NTP era, the first ends in 2036
+const ERA_SECONDS: i64 = 1 << 32;
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+// stratum 16 and above means the server is not synchronized
+const MAX_STRATUM: u8 = 15;
+// about a microsecond, as log2 seconds
+const PRECISION: i8 = -20;
+// see: https://datatracker.ietf.org/doc/html/rfc5905#section-7.3
+const LOCAL_REFERENCE_ID: [u8; 4] = *b"LOCL";
+
+/// Warning of a leap second in the last minute of the current day
+// see: https://datatracker.ietf.org/doc/html/rfc5905#section-7.3
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LeapIndicator {
+    NoWarning,
+    /// The last minute of the day has 61 seconds
+    AddSecond,
+    /// The last minute of the day has 59 seconds
+    DeleteSecond,
+    /// The server clock is not synchronized
+    Unsynchronized,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Reserved,
+    SymmetricActive,
+    SymmetricPassive,
+    Client,
+    Server,
+    Broadcast,
+    Control,
+    Private,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NTPTimestamp {
+    pub seconds: u32,
+    pub fraction: u32,
+}
+
+/// A 48 byte NTP packet without extension fields or authenticator
+// see: https://datatracker.ietf.org/doc/html/rfc5905#section-7.3
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NtpPacket {
+    pub leap: LeapIndicator,
+    pub version: u8,
+    pub mode: Mode,
+    pub stratum: u8,
+    /// log2 seconds between messages
+    pub poll: i8,
+    /// log2 seconds of the clock precision
+    pub precision: i8,
+    /// Round trip to the primary reference, NTP short format (16.16 seconds)
+    pub root_delay: u32,
+    /// Dispersion to the primary reference, NTP short format (16.16 seconds)
+    pub root_dispersion: u32,
+    pub reference_id: [u8; 4],
+    pub reference_time: NTPTimestamp,
+    pub origin_time: NTPTimestamp,
+    pub receive_time: NTPTimestamp,
+    pub transmit_time: NTPTimestamp,
+}
+
+#[derive(Debug)]
+pub enum NtpError {
+    Io(std::io::Error),
+    /// Fewer bytes than an NTP packet holds
+    Truncated(usize),
+    /// A response that is not from a server
+    UnexpectedMode(Mode),
+    /// The origin timestamp does not echo the transmit timestamp sent
+    OriginMismatch,
+    /// Stratum 0 with a kiss code such as RATE or DENY
+    KissOfDeath(String),
+    Unsynchronized,
+    /// The MAC is missing, made with another key or does not match
+    BadMac,
+    /// The server could not authenticate the request
+    CryptoNak,
+    /// The key ID is not in the keys file
+    UnknownKey(u32),
+}
+
+impl Display for NtpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NtpError::Io(err) => write!(f, "{err}"),
+            NtpError::Truncated(len) => write!(f, "truncated packet of {len} bytes"),
+            NtpError::UnexpectedMode(mode) => write!(f, "unexpected mode {mode:?}"),
+            NtpError::OriginMismatch => write!(f, "origin timestamp does not match the request"),
+            NtpError::KissOfDeath(code) => write!(f, "kiss-o'-death {code}"),
+            NtpError::Unsynchronized => write!(f, "server is not synchronized"),
+            NtpError::BadMac => write!(f, "authentication failed"),
+            NtpError::CryptoNak => write!(f, "server rejected the request's MAC"),
+            NtpError::UnknownKey(id) => write!(f, "key {id} is not in the keys file"),
+        }
+    }
+}
+
+impl Error for NtpError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            NtpError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for NtpError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl From<u8> for LeapIndicator {
+    fn from(value: u8) -> Self {
+        match value & 0b11 {
+            0 => LeapIndicator::NoWarning,
+            1 => LeapIndicator::AddSecond,
+            2 => LeapIndicator::DeleteSecond,
+            _ => LeapIndicator::Unsynchronized,
+        }
+    }
+}
+
+impl From<LeapIndicator> for u8 {
+    fn from(value: LeapIndicator) -> Self {
+        value as u8
+    }
+}
+
+impl From<u8> for Mode {
+    fn from(value: u8) -> Self {
+        match value & 0b111 {
+            0 => Mode::Reserved,
+            1 => Mode::SymmetricActive,
+            2 => Mode::SymmetricPassive,
+            3 => Mode::Client,
+            4 => Mode::Server,
+            5 => Mode::Broadcast,
+            6 => Mode::Control,
+            _ => Mode::Private,
+        }
+    }
+}
+
+impl From<Mode> for u8 {
+    fn from(value: Mode) -> Self {
+        value as u8
+    }
+}
+
+impl NTPTimestamp {
+    fn read(reader: &mut &[u8]) -> Result<Self, std::io::Error> {
+        let seconds = reader.read_u32::<BigEndian>()?;
+        let fraction = reader.read_u32::<BigEndian>()?;
+        Ok(NTPTimestamp { seconds, fraction })
+    }
+
+    fn write(&self, buf: &mut [u8]) {
+        buf[..4].copy_from_slice(&self.seconds.to_be_bytes());
+        buf[4..8].copy_from_slice(&self.fraction.to_be_bytes());
+    }
+
+    pub fn is_zero(&self) -> bool {
+        *self == NTPTimestamp::default()
+    }
+
+    /// Seconds since 1900. Timestamps carry no era, so those with the top
+    /// bit clear are taken to be past the 2036 rollover, which covers 1968
+    /// to 2104.
+    // see: https://datatracker.ietf.org/doc/html/rfc4330#section-3
+    fn era_seconds(&self) -> i64 {
+        match self.seconds & 0x8000_0000 {
+            0 => self.seconds as i64 + ERA_SECONDS,
+            _ => self.seconds as i64,
+        }
+    }
+}
+
+// fractions are 2^-32 seconds, rounding to the nearest nanosecond and back
+// gives the nanosecond that went in
+impl From<NTPTimestamp> for DateTime<Utc> {
+    fn from(ntp: NTPTimestamp) -> Self {
+        let nanos = (ntp.fraction as u64 * NANOS_PER_SECOND + (1 << 31)) >> 32;
+        let secs = ntp.era_seconds() - NTP_TO_UNIX_SECONDS + (nanos / NANOS_PER_SECOND) as i64;
+
+        DateTime::from_timestamp(secs, (nanos % NANOS_PER_SECOND) as u32)
+            .expect("1968 to 2104 is within the range of DateTime")
+    }
+}
+
+impl From<DateTime<Utc>> for NTPTimestamp {
+    fn from(dt: DateTime<Utc>) -> Self {
+        // leap seconds are the second before them in NTP time
+        let nanos = (dt.timestamp_subsec_nanos() as u64).min(NANOS_PER_SECOND - 1);
+        let fraction = ((nanos << 32) + NANOS_PER_SECOND / 2) / NANOS_PER_SECOND;
+
+        NTPTimestamp {
+            // only the seconds within the era are sent
+            seconds: (dt.timestamp() + NTP_TO_UNIX_SECONDS).rem_euclid(ERA_SECONDS) as u32,
+            fraction: fraction as u32,
+        }
+    }
+}
+
+/// Seconds in the NTP short format of root delay and root dispersion
+fn short_seconds(short: u32) -> f64 {
+    short as f64 / 65536.0
+}
+
+impl NtpPacket {
+    /// A version 4 client request, every other field zero
+    pub fn client() -> Self {
+        Self {
+            leap: LeapIndicator::NoWarning,
+            version: 4,
+            mode: Mode::Client,
+            stratum: 0,
+            poll: 0,
+            precision: 0,
+            root_delay: 0,
+            root_dispersion: 0,
+            reference_id: [0; 4],
+            reference_time: NTPTimestamp::default(),
+            origin_time: NTPTimestamp::default(),
+            receive_time: NTPTimestamp::default(),
+            transmit_time: NTPTimestamp::default(),
+        }
+    }
+
+    /// Reply to a client request, received at `rx`, from a server at
+    /// `stratum` whose clock was last set at `reference`.
+    /// The transmit timestamp is left to be set right before sending.
+    pub fn server(
+        request: &NtpPacket,
+        stratum: u8,
+        reference: DateTime<Utc>,
+        rx: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            leap: LeapIndicator::NoWarning,
+            version: request.version,
+            mode: Mode::Server,
+            stratum,
+            poll: request.poll,
+            precision: PRECISION,
+            // the local clock is the reference
+            root_delay: 0,
+            root_dispersion: 0,
+            reference_id: LOCAL_REFERENCE_ID,
+            reference_time: reference.into(),
+            origin_time: request.transmit_time,
+            receive_time: rx.into(),
+            transmit_time: NTPTimestamp::default(),
+        }
+    }
+
+    /// Decode the header of `data`, anything past it is ignored
+    pub fn parse(data: &[u8]) -> Result<Self, NtpError> {
+        if data.len() < NTP_MESSAGE_LENGTH {
+            return Err(NtpError::Truncated(data.len()));
+        }
+        let mut reader = data;
+        let first = reader.read_u8()?;
+        let stratum = reader.read_u8()?;
+        let poll = reader.read_i8()?;
+        let precision = reader.read_i8()?;
+        let root_delay = reader.read_u32::<BigEndian>()?;
+        let root_dispersion = reader.read_u32::<BigEndian>()?;
+        let mut reference_id = [0; 4];
+        reference_id.copy_from_slice(&reader[..4]);
+        reader = &reader[4..];
+
+        Ok(Self {
+            leap: LeapIndicator::from(first >> 6),
+            version: (first >> 3) & 0b111,
+            mode: Mode::from(first),
+            stratum,
+            poll,
+            precision,
+            root_delay,
+            root_dispersion,
+            reference_id,
+            reference_time: NTPTimestamp::read(&mut reader)?,
+            origin_time: NTPTimestamp::read(&mut reader)?,
+            receive_time: NTPTimestamp::read(&mut reader)?,
+            transmit_time: NTPTimestamp::read(&mut reader)?,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; NTP_MESSAGE_LENGTH] {
+        let mut data = [0; NTP_MESSAGE_LENGTH];
+        data[0] = u8::from(self.leap) << 6 | (self.version & 0b111) << 3 | u8::from(self.mode);
+        data[1] = self.stratum;
+        data[2] = self.poll as u8;
+        data[3] = self.precision as u8;
+        data[4..8].copy_from_slice(&self.root_delay.to_be_bytes());
+        data[8..12].copy_from_slice(&self.root_dispersion.to_be_bytes());
+        data[12..16].copy_from_slice(&self.reference_id);
+        self.reference_time.write(&mut data[16..24]);
+        self.origin_time.write(&mut data[24..32]);
+        self.receive_time.write(&mut data[32..40]);
+        self.transmit_time.write(&mut data[40..48]);
+        data
+    }
+
+    /// Check that `self` is a usable server reply to `request`
+    pub fn validate_response(&self, request: &NtpPacket) -> Result<(), NtpError> {
+        if self.mode != Mode::Server {
+            return Err(NtpError::UnexpectedMode(self.mode));
+        }
+        if self.origin_time != request.transmit_time {
+            return Err(NtpError::OriginMismatch);
+        }
+        if self.stratum == 0 {
+            return Err(NtpError::KissOfDeath(self.kiss_code()));
+        }
+        if self.leap == LeapIndicator::Unsynchronized || self.stratum > MAX_STRATUM {
+            return Err(NtpError::Unsynchronized);
+        }
+        Ok(())
+    }
+
+    /// The reference ID read as ASCII, a kiss code in stratum 0 packets
+    pub fn kiss_code(&self) -> String {
+        String::from_utf8_lossy(&self.reference_id)
+            .trim_end_matches('\0')
+            .to_string()
+    }
+
+    pub fn root_delay_seconds(&self) -> f64 {
+        short_seconds(self.root_delay)
+    }
+
+    pub fn root_dispersion_seconds(&self) -> f64 {
+        short_seconds(self.root_dispersion)
+    }
+
+    /// Reference IDs of primary servers name their source, secondary ones
+    /// carry the IPv4 address of their upstream server
+    pub fn reference(&self) -> String {
+        match self.stratum {
+            0 | 1 => self.kiss_code(),
+            _ => Ipv4Addr::from(self.reference_id).to_string(),
+        }
+    }
+}
+
+impl Display for NtpPacket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let time = |ts: NTPTimestamp| match ts.is_zero() {
+            true => "-".to_string(),
+            false => DateTime::<Utc>::from(ts).to_rfc3339(),
+        };
+        writeln!(
+            f,
+            "leap: {:?}, version: {}, mode: {:?}, stratum: {}",
+            self.leap, self.version, self.mode, self.stratum
+        )?;
+        writeln!(
+            f,
+            "poll: 2^{}s, precision: 2^{}s, root delay: {:.6}s, root dispersion: {:.6}s",
+            self.poll,
+            self.precision,
+            self.root_delay_seconds(),
+            self.root_dispersion_seconds()
+        )?;
+        writeln!(f, "reference id: {}", self.reference())?;
+        writeln!(f, "reference: {}", time(self.reference_time))?;
+        writeln!(f, "origin:    {}", time(self.origin_time))?;
+        writeln!(f, "receive:   {}", time(self.receive_time))?;
+        write!(f, "transmit:  {}", time(self.transmit_time))
+    }
+}
+
+#[cfg(test)]
+mod packet_test {
+    use super::*;
+
+    fn server_reply() -> NtpPacket {
+        NtpPacket {
+            leap: LeapIndicator::AddSecond,
+            version: 4,
+            mode: Mode::Server,
+            stratum: 2,
+            poll: 6,
+            precision: -23,
+            root_delay: 0x0001_8000,
+            root_dispersion: 0x0000_4000,
+            reference_id: [192, 0, 2, 1],
+            reference_time: NTPTimestamp {
+                seconds: 1,
+                fraction: 2,
+            },
+            origin_time: NTPTimestamp::default(),
+            receive_time: NTPTimestamp {
+                seconds: 5,
+                fraction: 6,
+            },
+            transmit_time: NTPTimestamp {
+                seconds: 7,
+                fraction: 8,
+            },
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let packet = server_reply();
+        let data = packet.to_bytes();
+        assert_eq!(data[0], 0b01_100_100);
+        assert_eq!(data[3], (-23_i8) as u8);
+        assert_eq!(NtpPacket::parse(&data).unwrap(), packet);
+
+        assert_eq!(packet.root_delay_seconds(), 1.5);
+        assert_eq!(packet.root_dispersion_seconds(), 0.25);
+        assert_eq!(packet.reference(), "192.0.2.1");
+        assert!(matches!(
+            NtpPacket::parse(&data[..47]),
+            Err(NtpError::Truncated(47))
+        ));
+    }
+
+    #[test]
+    fn validation() {
+        let request = NtpPacket::client();
+        assert!(server_reply().validate_response(&request).is_ok());
+
+        let mut reply = server_reply();
+        reply.mode = Mode::Broadcast;
+        assert!(matches!(
+            reply.validate_response(&request),
+            Err(NtpError::UnexpectedMode(Mode::Broadcast))
+        ));
+
+        let mut reply = server_reply();
+        reply.origin_time.fraction = 1;
+        assert!(matches!(
+            reply.validate_response(&request),
+            Err(NtpError::OriginMismatch)
+        ));
+
+        let mut reply = server_reply();
+        reply.stratum = 0;
+        reply.reference_id = *b"RATE";
+        assert!(matches!(
+            reply.validate_response(&request),
+            Err(NtpError::KissOfDeath(code)) if code == "RATE"
+        ));
+
+        let mut reply = server_reply();
+        reply.leap = LeapIndicator::Unsynchronized;
+        assert!(matches!(
+            reply.validate_response(&request),
+            Err(NtpError::Unsynchronized)
+        ));
+    }
+
+    #[test]
+    fn timestamp_roundtrip() {
+        let now = Utc::now();
+        let back: DateTime<Utc> = NTPTimestamp::from(now).into();
+        assert_eq!(back, now);
+
+        for nanos in [0, 1, 2, 123_456_789, 500_000_000, 999_999_999] {
+            let dt = DateTime::from_timestamp(1_700_000_000, nanos).unwrap();
+            assert_eq!(DateTime::<Utc>::from(NTPTimestamp::from(dt)), dt);
+        }
+    }
+
+    #[test]
+    fn timestamp_conversions() {
+        let ntp = |seconds, fraction| NTPTimestamp { seconds, fraction };
+        let utc = |text: &str| text.parse::<DateTime<Utc>>().unwrap();
+
+        assert_eq!(
+            NTPTimestamp::from(utc("1970-01-01T00:00:00.5Z")),
+            ntp(2_208_988_800, 0x8000_0000)
+        );
+        // the largest fraction rounds up into the next second
+        assert_eq!(
+            DateTime::<Utc>::from(ntp(2_208_988_800, u32::MAX)),
+            utc("1970-01-01T00:00:01Z")
+        );
+        assert_eq!(
+            DateTime::<Utc>::from(ntp(u32::MAX, 0)),
+            utc("2036-02-07T06:28:15Z")
+        );
+        assert_eq!(
+            DateTime::<Utc>::from(ntp(0, 0)),
+            utc("2036-02-07T06:28:16Z")
+        );
+
+        let after_rollover = utc("2040-01-01T00:00:00.25Z");
+        let wrapped = NTPTimestamp::from(after_rollover);
+        assert_eq!(wrapped, ntp(123_010_304, 0x4000_0000));
+        assert_eq!(DateTime::<Utc>::from(wrapped), after_rollover);
+    }
+}
@@ -0,0 +1,381 @@
+use std::{fmt::Display, fs, path::Path};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use color_eyre::{eyre::Context, Report};
+use serde::{Deserialize, Serialize};
+
+use crate::{leap::LeapStatus, packet::LeapIndicator};
+
+/// Where `ntp` and `daemon` record their last sync for `status`
+pub const DEFAULT_STATE_FILE: &str = "/var/lib/clock/last-sync.json";
+
+/// What one server answered, times in milliseconds
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerReport {
+    pub server: String,
+    /// Whether a usable reply came back
+    pub reachable: bool,
+    pub stratum: Option<u8>,
+    pub offset_ms: Option<f64>,
+    pub delay_ms: Option<f64>,
+    /// Root distance, the true offset lies within `offset ± distance`
+    pub distance_ms: Option<f64>,
+    /// Leap second the server announces for the end of the day
+    pub leap: Option<LeapIndicator>,
+    /// Whether the offset counted towards the correction
+    pub selected: bool,
+    /// Why the query failed
+    pub error: Option<String>,
+    /// Why selection left the server out
+    pub rejected: Option<String>,
+}
+
+impl ServerReport {
+    pub fn unreachable(server: String, error: String) -> Self {
+        Self {
+            server,
+            reachable: false,
+            stratum: None,
+            offset_ms: None,
+            delay_ms: None,
+            distance_ms: None,
+            leap: None,
+            selected: false,
+            error: Some(error),
+            rejected: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "lowercase")]
+pub enum Correction {
+    Slew {
+        offset_ms: f64,
+        /// Roughly how long the kernel takes to apply it
+        duration_s: f64,
+    },
+    Step {
+        offset_ms: f64,
+        to: DateTime<Utc>,
+        /// Slewing was asked for, but the offset was above this threshold
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        threshold_ms: Option<f64>,
+    },
+}
+
+impl Display for Correction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Correction::Slew {
+                offset_ms,
+                duration_s,
+            } => write!(
+                f,
+                "slew the clock by {offset_ms:+.3}ms over about {duration_s:.0}s"
+            ),
+            Correction::Step {
+                offset_ms,
+                to,
+                threshold_ms,
+            } => {
+                write!(
+                    f,
+                    "step the clock by {offset_ms:+.3}ms to {}",
+                    to.to_rfc3339()
+                )?;
+                match threshold_ms {
+                    Some(threshold) => write!(f, ", above the {threshold}ms step threshold"),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+/// One round of querying the servers and correcting the clock
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SyncReport {
+    pub time: DateTime<Utc>,
+    pub servers: Vec<ServerReport>,
+    /// Offset of the local clock the selected servers agree on, positive
+    /// when it is behind
+    pub offset_ms: Option<f64>,
+    pub correction: Option<Correction>,
+    /// Leap second announced by most of the selected servers
+    pub leap: LeapIndicator,
+    /// Whether the kernel holds a leap second armed by `ntp` or `daemon`,
+    /// which a later run withdraws once the servers stop announcing it
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub leap_armed: bool,
+    pub dry_run: bool,
+    /// Frequency correction of the daemon, positive speeds the clock up
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frequency_ppm: Option<f64>,
+    /// Poll interval of the daemon
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub poll_s: Option<u64>,
+    /// Why the clock was left alone
+    pub error: Option<String>,
+}
+
+impl SyncReport {
+    pub fn new(servers: Vec<ServerReport>, dry_run: bool) -> Self {
+        Self {
+            time: Utc::now(),
+            leap: vote_leap(&servers),
+            leap_armed: false,
+            servers,
+            offset_ms: None,
+            correction: None,
+            dry_run,
+            frequency_ppm: None,
+            poll_s: None,
+            error: None,
+        }
+    }
+
+    /// Record the outcome of correcting the clock, keeping every cause of
+    /// a failure
+    pub fn corrected(&mut self, result: &Result<Correction, Report>) {
+        match result {
+            Ok(correction) => self.correction = Some(correction.clone()),
+            Err(err) => {
+                let causes: Vec<String> = err.chain().map(ToString::to_string).collect();
+                self.error = Some(causes.join(": "));
+            }
+        }
+    }
+
+    /// The selected server closest to the true time
+    pub fn reference(&self) -> Option<&ServerReport> {
+        self.servers
+            .iter()
+            .filter(|server| server.selected)
+            .min_by(|a, b| {
+                a.distance_ms
+                    .unwrap_or(f64::INFINITY)
+                    .total_cmp(&b.distance_ms.unwrap_or(f64::INFINITY))
+            })
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("reports always serialize")
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Report> {
+        let text = match fs::read_to_string(path) {
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Err(Report::msg(format!(
+                    "no sync recorded in {} yet",
+                    path.display()
+                )));
+            }
+            result => result?,
+        };
+        serde_json::from_str(&text)
+            .wrap_err_with(|| format!("invalid state file {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), std::io::Error> {
+        // nothing installs /var/lib/clock, the first save makes it
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        // replace the file at once, so status never reads half of it
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, self.to_json())?;
+        fs::rename(&tmp, path)
+    }
+}
+
+/// A leap second more than half of the selected servers announce, so
+/// a single falseticker cannot shift the clock by a second
+fn vote_leap(servers: &[ServerReport]) -> LeapIndicator {
+    let selected: Vec<_> = servers.iter().filter(|server| server.selected).collect();
+    [LeapIndicator::AddSecond, LeapIndicator::DeleteSecond]
+        .into_iter()
+        .find(|&leap| {
+            let votes = selected.iter().filter(|s| s.leap == Some(leap)).count();
+            votes * 2 > selected.len()
+        })
+        .unwrap_or(LeapIndicator::NoWarning)
+}
+
+/// Laid out like `chronyc tracking`
+// see: https://chrony-project.org/doc/4.5/chronyc.html#tracking
+impl Display for SyncReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reference = self.reference();
+        match reference {
+            Some(server) => writeln!(f, "Reference       : {}", server.server)?,
+            None => writeln!(f, "Reference       : none")?,
+        }
+        // one more than the server the clock follows, like any NTP client
+        match reference.and_then(|server| server.stratum) {
+            Some(stratum) => writeln!(f, "Stratum         : {}", stratum.saturating_add(1))?,
+            None => writeln!(f, "Stratum         : 0")?,
+        }
+        writeln!(
+            f,
+            "Sync time (UTC) : {}",
+            self.time.to_rfc3339_opts(SecondsFormat::Secs, true)
+        )?;
+        match self.offset_ms {
+            Some(offset) if offset > 0.0 => {
+                writeln!(f, "Last offset     : {offset:+.3}ms, the clock was slow")?
+            }
+            Some(offset) if offset < 0.0 => {
+                writeln!(f, "Last offset     : {offset:+.3}ms, the clock was fast")?
+            }
+            Some(offset) => writeln!(f, "Last offset     : {offset:+.3}ms")?,
+            None => writeln!(f, "Last offset     : unknown")?,
+        }
+        match (&self.correction, &self.error) {
+            (Some(correction), _) if self.dry_run => {
+                writeln!(f, "Correction      : would {correction}, dry run")?
+            }
+            (Some(correction), _) => writeln!(f, "Correction      : {correction}")?,
+            (None, Some(err)) => writeln!(f, "Correction      : none, {err}")?,
+            (None, None) => writeln!(f, "Correction      : none")?,
+        }
+        if let Some(frequency) = self.frequency_ppm {
+            // the correction makes up for the clock running the other way
+            let direction = if frequency >= 0.0 { "slow" } else { "fast" };
+            writeln!(
+                f,
+                "Frequency       : {:.3} ppm {direction}",
+                frequency.abs()
+            )?;
+        }
+        if let Some(poll) = self.poll_s {
+            writeln!(f, "Update interval : {poll}s")?;
+        }
+        match self.leap {
+            LeapIndicator::AddSecond => writeln!(f, "Leap status     : Insert second")?,
+            LeapIndicator::DeleteSecond => writeln!(f, "Leap status     : Delete second")?,
+            _ => writeln!(f, "Leap status     : Normal")?,
+        }
+        let reachable = self.servers.iter().filter(|s| s.reachable).count();
+        let selected = self.servers.iter().filter(|s| s.selected).count();
+        write!(
+            f,
+            "Servers         : {} queried, {reachable} reachable, {selected} selected",
+            self.servers.len()
+        )
+    }
+}
+
+/// What `status` shows, the last sync and the leap seconds ahead
+#[derive(Debug, Serialize)]
+pub struct Status {
+    #[serde(flatten)]
+    pub report: SyncReport,
+    /// Unknown without a leap seconds list
+    pub leap_seconds: Option<LeapStatus>,
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.report)?;
+        match &self.leap_seconds {
+            Some(leaps) => write!(f, "\n{leaps}"),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod report_test {
+    use super::*;
+
+    fn report() -> SyncReport {
+        let answered = |server: &str, offset: f64, distance: f64, selected: bool| ServerReport {
+            server: server.to_string(),
+            reachable: true,
+            stratum: Some(1),
+            offset_ms: Some(offset),
+            delay_ms: Some(distance * 2.0),
+            distance_ms: Some(distance),
+            leap: Some(LeapIndicator::AddSecond),
+            selected,
+            error: None,
+            rejected: None,
+        };
+        let mut report = SyncReport::new(
+            vec![
+                answered("a:123", 10.0, 8.0, true),
+                answered("b:123", 12.0, 4.0, true),
+                ServerReport {
+                    rejected: Some("falseticker".to_string()),
+                    ..answered("c:123", 500.0, 4.0, false)
+                },
+                ServerReport::unreachable("d:123".to_string(), "timed out".to_string()),
+            ],
+            true,
+        );
+        report.time = "2024-03-10T12:00:00Z".parse().unwrap();
+        report.offset_ms = Some(11.5);
+        report.corrected(&Ok(Correction::Slew {
+            offset_ms: 11.5,
+            duration_s: 23.0,
+        }));
+        report.frequency_ppm = Some(-7.5);
+        report.poll_s = Some(64);
+        report
+    }
+
+    #[test]
+    fn json_roundtrip() {
+        let mut report = report();
+        let json: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+        assert!(json.get("leap_armed").is_none());
+        report.leap_armed = true;
+        let json: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+        assert_eq!(json["leap_armed"], true);
+        assert_eq!(json["correction"]["method"], "slew");
+        assert_eq!(json["servers"][3]["reachable"], false);
+        assert_eq!(json["servers"][3]["error"], "timed out");
+        assert_eq!(json["time"], "2024-03-10T12:00:00Z");
+
+        let path = std::env::temp_dir().join(format!("clock-state-{}", std::process::id()));
+        assert!(SyncReport::load(&path).is_err());
+        report.save(&path).unwrap();
+        assert_eq!(SyncReport::load(&path).unwrap(), report);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn save_creates_directory() {
+        let dir = std::env::temp_dir().join(format!("clock-state-dir-{}", std::process::id()));
+        let path = dir.join("lib").join("last-sync.json");
+        report().save(&path).unwrap();
+        assert_eq!(SyncReport::load(&path).unwrap(), report());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tracking() {
+        let mut report = report();
+        let text = report.to_string();
+        assert!(text.contains("Reference       : b:123\n"), "{text}");
+        assert!(text.contains("Stratum         : 2\n"));
+        assert!(text.contains("Last offset     : +11.500ms, the clock was slow\n"));
+        assert!(text.contains("would slew the clock by +11.500ms over about 23s, dry run"));
+        assert!(text.contains("Frequency       : 7.500 ppm fast\n"));
+        assert!(text.contains("Leap status     : Insert second\n"));
+        assert!(text.ends_with("4 queried, 3 reachable, 2 selected"));
+
+        // one of two selected servers is no majority
+        report.servers[1].leap = Some(LeapIndicator::NoWarning);
+        assert_eq!(vote_leap(&report.servers), LeapIndicator::NoWarning);
+
+        report.correction = None;
+        report.corrected(&Err(
+            Report::msg("not enough survivors").wrap_err("unable to sync")
+        ));
+        assert!(report
+            .to_string()
+            .contains("Correction      : none, unable to sync: not enough survivors\n"));
+    }
+}
@@ -0,0 +1,555 @@
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt::Display,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Duration, Utc};
+use ed25519_dalek::{Signature, VerifyingKey};
+use sha2::{Digest, Sha512};
+use tracing::debug;
+
+use crate::config::Server;
+
+// The protocol as Google and Cloudflare first served it, without the
+// framing and versions of the IETF drafts
+// see: https://roughtime.googlesource.com/roughtime/+/HEAD/PROTOCOL.md
+
+pub const DEFAULT_PORT: u16 = 2002;
+
+pub const NONCE_LENGTH: usize = 64;
+
+/// Requests are padded to this many bytes, so a response is never larger
+/// than the request it answers
+const MIN_REQUEST_LENGTH: usize = 1024;
+
+/// Longest response read, far more than a server batching a million requests sends
+const MAX_RESPONSE_LENGTH: usize = 4096;
+
+const DELEGATION_CONTEXT: &[u8] = b"RoughTime v1 delegation signature--\0";
+const RESPONSE_CONTEXT: &[u8] = b"RoughTime v1 response signature\0";
+
+/// Tags are compared as little endian numbers, the order they are encoded in
+type Tag = u32;
+
+const fn tag(name: &[u8; 4]) -> Tag {
+    u32::from_le_bytes(*name)
+}
+
+const SIG: Tag = tag(b"SIG\0");
+const NONC: Tag = tag(b"NONC");
+const PAD: Tag = tag(b"PAD\xff");
+const PATH: Tag = tag(b"PATH");
+const SREP: Tag = tag(b"SREP");
+const CERT: Tag = tag(b"CERT");
+const INDX: Tag = tag(b"INDX");
+const RADI: Tag = tag(b"RADI");
+const MIDP: Tag = tag(b"MIDP");
+const ROOT: Tag = tag(b"ROOT");
+const DELE: Tag = tag(b"DELE");
+const MINT: Tag = tag(b"MINT");
+const MAXT: Tag = tag(b"MAXT");
+const PUBK: Tag = tag(b"PUBK");
+
+fn tag_name(tag: Tag) -> String {
+    String::from_utf8_lossy(&tag.to_le_bytes())
+        .trim_end_matches(['\0', '\u{fffd}'])
+        .to_string()
+}
+
+#[derive(Debug)]
+pub enum RoughtimeError {
+    Io(io::Error),
+    Malformed(String),
+    MissingTag(Tag),
+    /// The signature of the delegation or of the response does not check out
+    BadSignature(&'static str),
+    /// The nonce is not in the tree the server signed
+    BadPath,
+    /// The time is outside the validity of the delegated key
+    OutsideValidity,
+}
+
+impl Display for RoughtimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RoughtimeError::Io(err) => write!(f, "{err}"),
+            RoughtimeError::Malformed(reason) => write!(f, "malformed message: {reason}"),
+            RoughtimeError::MissingTag(tag) => write!(f, "missing tag {}", tag_name(*tag)),
+            RoughtimeError::BadSignature(what) => write!(f, "invalid {what} signature"),
+            RoughtimeError::BadPath => write!(f, "the response is not for this request"),
+            RoughtimeError::OutsideValidity => {
+                write!(f, "the time is outside the validity of the server's key")
+            }
+        }
+    }
+}
+
+impl Error for RoughtimeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RoughtimeError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RoughtimeError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+/// Values by tag, encoded as the number of tags, the offset of every value
+/// but the first, the tags and then the values, all little endian
+type Message = BTreeMap<Tag, Vec<u8>>;
+
+fn encode(message: &Message) -> Vec<u8> {
+    let mut data = (message.len() as u32).to_le_bytes().to_vec();
+    let mut offset = 0;
+    for value in message.values().take(message.len().saturating_sub(1)) {
+        offset += value.len() as u32;
+        data.extend_from_slice(&offset.to_le_bytes());
+    }
+    for tag in message.keys() {
+        data.extend_from_slice(&tag.to_le_bytes());
+    }
+    for value in message.values() {
+        data.extend_from_slice(value);
+    }
+    data
+}
+
+fn decode(data: &[u8]) -> Result<Message, RoughtimeError> {
+    let malformed = |reason: &str| RoughtimeError::Malformed(reason.to_string());
+    let word = |i: usize| u32::from_le_bytes(data[i * 4..i * 4 + 4].try_into().unwrap());
+
+    if data.len() < 4 || !data.len().is_multiple_of(4) {
+        return Err(malformed("length is not a multiple of 4"));
+    }
+    let count = word(0) as usize;
+    if count == 0 {
+        return Ok(Message::new());
+    }
+    let header_words = 2 * count;
+    if data.len() / 4 < header_words {
+        return Err(malformed("header longer than the message"));
+    }
+
+    let values = &data[header_words * 4..];
+    let offsets: Vec<usize> = std::iter::once(0)
+        .chain((1..count).map(|i| word(i) as usize))
+        .chain(std::iter::once(values.len()))
+        .collect();
+    let tags: Vec<Tag> = (count..header_words).map(word).collect();
+
+    if offsets.windows(2).any(|w| w[0] > w[1] || w[1] % 4 != 0) {
+        return Err(malformed("offsets are out of order or unaligned"));
+    }
+    if tags.windows(2).any(|w| w[0] >= w[1]) {
+        return Err(malformed("tags are out of order"));
+    }
+    Ok(tags
+        .into_iter()
+        .zip(offsets.windows(2))
+        .map(|(tag, w)| (tag, values[w[0]..w[1]].to_vec()))
+        .collect())
+}
+
+fn field(message: &Message, tag: Tag) -> Result<&[u8], RoughtimeError> {
+    message
+        .get(&tag)
+        .map(Vec::as_slice)
+        .ok_or(RoughtimeError::MissingTag(tag))
+}
+
+fn fixed<const N: usize>(message: &Message, tag: Tag) -> Result<[u8; N], RoughtimeError> {
+    field(message, tag)?
+        .try_into()
+        .map_err(|_| RoughtimeError::Malformed(format!("{} is not {N} bytes", tag_name(tag))))
+}
+
+/// A request for the time carrying `nonce`, padded to the minimum length
+fn request(nonce: &[u8; NONCE_LENGTH]) -> Vec<u8> {
+    let mut message = Message::from([(NONC, nonce.to_vec()), (PAD, Vec::new())]);
+    let padding = MIN_REQUEST_LENGTH - encode(&message).len();
+    message.insert(PAD, vec![0; padding]);
+    encode(&message)
+}
+
+/// SHA-512 of a leaf or a node of the Merkle tree, told apart by `prefix`
+fn tree_hash(prefix: u8, parts: &[&[u8]]) -> [u8; 64] {
+    let hash = parts
+        .iter()
+        .fold(Sha512::new().chain_update([prefix]), |hash, part| {
+            hash.chain_update(part)
+        });
+    hash.finalize().into()
+}
+
+/// Time given by a server, accurate to within `radius`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SignedTime {
+    pub midpoint: DateTime<Utc>,
+    pub radius: Duration,
+}
+
+/// Check that `response` answers the request carrying `nonce`, signed with
+/// a key that `public_key` delegated to
+pub fn verify(
+    response: &[u8],
+    nonce: &[u8; NONCE_LENGTH],
+    public_key: &VerifyingKey,
+) -> Result<SignedTime, RoughtimeError> {
+    let response = decode(response)?;
+
+    // the long-term key signs a short-lived key kept online
+    let cert = decode(field(&response, CERT)?)?;
+    let dele_bytes = field(&cert, DELE)?;
+    let signature = Signature::from_bytes(&fixed(&cert, SIG)?);
+    public_key
+        .verify_strict(&[DELEGATION_CONTEXT, dele_bytes].concat(), &signature)
+        .map_err(|_| RoughtimeError::BadSignature("delegation"))?;
+    let dele = decode(dele_bytes)?;
+    let online_key = VerifyingKey::from_bytes(&fixed(&dele, PUBK)?)
+        .map_err(|_| RoughtimeError::BadSignature("delegation"))?;
+
+    let srep_bytes = field(&response, SREP)?;
+    let signature = Signature::from_bytes(&fixed(&response, SIG)?);
+    online_key
+        .verify_strict(&[RESPONSE_CONTEXT, srep_bytes].concat(), &signature)
+        .map_err(|_| RoughtimeError::BadSignature("response"))?;
+    let srep = decode(srep_bytes)?;
+
+    // one signature covers a batch of requests, the path leads from this
+    // nonce to the root of their tree
+    let path = field(&response, PATH)?;
+    if !path.len().is_multiple_of(64) {
+        return Err(RoughtimeError::Malformed("PATH is not whole hashes".into()));
+    }
+    let mut index = u32::from_le_bytes(fixed(&response, INDX)?);
+    let mut hash = tree_hash(0, &[nonce]);
+    for node in path.chunks(64) {
+        hash = match index & 1 {
+            0 => tree_hash(1, &[&hash, node]),
+            _ => tree_hash(1, &[node, &hash]),
+        };
+        index >>= 1;
+    }
+    if index != 0 || hash != fixed::<64>(&srep, ROOT)? {
+        return Err(RoughtimeError::BadPath);
+    }
+
+    let midpoint = u64::from_le_bytes(fixed(&srep, MIDP)?);
+    let min_time = u64::from_le_bytes(fixed(&dele, MINT)?);
+    let max_time = u64::from_le_bytes(fixed(&dele, MAXT)?);
+    if !(min_time..=max_time).contains(&midpoint) {
+        return Err(RoughtimeError::OutsideValidity);
+    }
+    Ok(SignedTime {
+        midpoint: i64::try_from(midpoint)
+            .ok()
+            .and_then(DateTime::from_timestamp_micros)
+            .ok_or(RoughtimeError::OutsideValidity)?,
+        radius: Duration::microseconds(u32::from_le_bytes(fixed(&srep, RADI)?).into()),
+    })
+}
+
+/// Ed25519 public key in base64, as servers publish them, or in hex
+pub fn parse_public_key(text: &str) -> Result<VerifyingKey, String> {
+    let bytes = match text.len() {
+        64 => (0..64)
+            .step_by(2)
+            .map(|i| u8::from_str_radix(text.get(i..i + 2).unwrap_or("?"), 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|err| format!("invalid hex key: {err}"))?,
+        _ => STANDARD
+            .decode(text)
+            .map_err(|err| format!("invalid base64 key: {err}"))?,
+    };
+    let bytes: [u8; 32] = bytes
+        .try_into()
+        .map_err(|bytes: Vec<u8>| format!("expected 32 key bytes, not {}", bytes.len()))?;
+    VerifyingKey::from_bytes(&bytes).map_err(|err| format!("invalid key: {err}"))
+}
+
+/// Server time measured against the local clock
+#[derive(Debug, Clone, Copy)]
+pub struct RoughtimeResult {
+    pub time: SignedTime,
+    /// Local clock when the request went out and the response came in
+    pub t1: DateTime<Utc>,
+    pub t4: DateTime<Utc>,
+}
+
+impl RoughtimeResult {
+    /// Milliseconds the local clock is behind the server
+    pub fn offset(&self) -> f64 {
+        let local = self.t1 + (self.t4 - self.t1) / 2;
+        (self.time.midpoint - local)
+            .num_microseconds()
+            .unwrap_or(i64::MAX) as f64
+            / 1000.0
+    }
+
+    /// Milliseconds the offset may be off by, the server's radius plus half
+    /// the round trip
+    pub fn uncertainty(&self) -> f64 {
+        let microseconds = self.time.radius + (self.t4 - self.t1) / 2;
+        microseconds.num_microseconds().unwrap_or(i64::MAX) as f64 / 1000.0
+    }
+}
+
+fn roundtrip(
+    addr: SocketAddr,
+    server: &Server,
+    public_key: &VerifyingKey,
+) -> Result<RoughtimeResult, RoughtimeError> {
+    let mut nonce = [0; NONCE_LENGTH];
+    getrandom::fill(&mut nonce).map_err(|err| io::Error::other(err.to_string()))?;
+
+    let local: SocketAddr = match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let udp = UdpSocket::bind(local)?;
+    udp.connect(addr)?;
+    udp.set_read_timeout(Some(server.timeout))?;
+
+    let mut response = [0; MAX_RESPONSE_LENGTH];
+    let t1 = Utc::now();
+    udp.send(&request(&nonce))?;
+    let len = udp.recv(&mut response)?;
+    let t4 = Utc::now();
+
+    let time = verify(&response[..len], &nonce, public_key)?;
+    Ok(RoughtimeResult { time, t1, t4 })
+}
+
+/// Ask `server` for the time, trying each of its addresses and retrying
+/// failures up to its retry count. Invalid responses are not retried.
+pub fn query(
+    server: &Server,
+    public_key: &VerifyingKey,
+) -> Result<RoughtimeResult, RoughtimeError> {
+    let addrs: Vec<SocketAddr> = (server.host.as_str(), server.port)
+        .to_socket_addrs()?
+        .collect();
+    if addrs.is_empty() {
+        let err = io::Error::new(io::ErrorKind::NotFound, "no addresses found");
+        return Err(err.into());
+    }
+
+    let mut attempt = 0;
+    loop {
+        let mut last_err = None;
+        for &addr in &addrs {
+            match roundtrip(addr, server, public_key) {
+                Err(RoughtimeError::Io(err)) => {
+                    debug!("{server} => {addr} failed: {err}");
+                    last_err = Some(err);
+                }
+                result => return result,
+            }
+        }
+        let err = last_err.expect("at least one address was tried");
+        if attempt == server.retries {
+            return Err(err.into());
+        }
+        attempt += 1;
+        debug!("{server} => retry {attempt} after: {err}");
+    }
+}
+
+#[cfg(test)]
+mod roughtime_test {
+    use std::thread;
+
+    use ed25519_dalek::{Signer, SigningKey};
+
+    use super::*;
+
+    /// A server answering batches of requests, signing with a key delegated
+    /// by a fixed long-term key
+    struct TestServer {
+        online_key: SigningKey,
+        cert: Vec<u8>,
+        radius: u32,
+    }
+
+    impl TestServer {
+        fn root_key() -> SigningKey {
+            SigningKey::from_bytes(&[7; 32])
+        }
+
+        fn new(min_time: u64, max_time: u64) -> Self {
+            let online_key = SigningKey::from_bytes(&[9; 32]);
+            let dele = encode(&Message::from([
+                (MINT, min_time.to_le_bytes().to_vec()),
+                (MAXT, max_time.to_le_bytes().to_vec()),
+                (PUBK, online_key.verifying_key().to_bytes().to_vec()),
+            ]));
+            let signature = Self::root_key().sign(&[DELEGATION_CONTEXT, &dele].concat());
+            let cert = encode(&Message::from([
+                (SIG, signature.to_bytes().to_vec()),
+                (DELE, dele),
+            ]));
+            Self {
+                online_key,
+                cert,
+                radius: 1_000_000,
+            }
+        }
+
+        /// One response for each nonce, all signed at once
+        fn respond(&self, nonces: &[[u8; NONCE_LENGTH]], now: DateTime<Utc>) -> Vec<Vec<u8>> {
+            let mut levels = vec![nonces
+                .iter()
+                .map(|nonce| tree_hash(0, &[nonce]))
+                .collect::<Vec<_>>()];
+            while levels.last().unwrap().len() > 1 {
+                let level = levels.last().unwrap();
+                let next = level
+                    .chunks(2)
+                    .map(|pair| tree_hash(1, &[&pair[0], pair.get(1).unwrap_or(&pair[0])]))
+                    .collect();
+                levels.push(next);
+            }
+
+            let srep = encode(&Message::from([
+                (RADI, self.radius.to_le_bytes().to_vec()),
+                (MIDP, (now.timestamp_micros() as u64).to_le_bytes().to_vec()),
+                (ROOT, levels.last().unwrap()[0].to_vec()),
+            ]));
+            let signature = self.online_key.sign(&[RESPONSE_CONTEXT, &srep].concat());
+
+            (0..nonces.len())
+                .map(|index| {
+                    let path: Vec<u8> = levels[..levels.len() - 1]
+                        .iter()
+                        .enumerate()
+                        .flat_map(|(depth, level)| {
+                            // a node left without a pair is paired with itself
+                            let sibling = (index >> depth) ^ 1;
+                            *level.get(sibling).unwrap_or_else(|| &level[sibling - 1])
+                        })
+                        .collect();
+                    encode(&Message::from([
+                        (SIG, signature.to_bytes().to_vec()),
+                        (PATH, path),
+                        (SREP, srep.clone()),
+                        (CERT, self.cert.clone()),
+                        (INDX, (index as u32).to_le_bytes().to_vec()),
+                    ]))
+                })
+                .collect()
+        }
+
+        fn serve(self, socket: UdpSocket) {
+            let mut data = [0; MIN_REQUEST_LENGTH];
+            loop {
+                let Ok((len, peer)) = socket.recv_from(&mut data) else {
+                    return;
+                };
+                let nonce = decode(&data[..len])
+                    .ok()
+                    .and_then(|request| fixed::<NONCE_LENGTH>(&request, NONC).ok());
+                if let (Some(nonce), MIN_REQUEST_LENGTH) = (nonce, len) {
+                    let response = self.respond(&[nonce], Utc::now()).remove(0);
+                    socket.send_to(&response, peer).unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn message_roundtrip() {
+        let message = Message::from([(NONC, vec![1; 64]), (SIG, vec![2; 4]), (PAD, Vec::new())]);
+        let data = encode(&message);
+        // SIG sorts first as its last byte is zero
+        assert_eq!(&data[12..16], b"SIG\0");
+        assert_eq!(decode(&data).unwrap(), message);
+        assert_eq!(request(&[0; NONCE_LENGTH]).len(), MIN_REQUEST_LENGTH);
+
+        assert!(decode(&[]).is_err());
+        assert!(decode(&[2, 0, 0, 0]).is_err());
+        let mut unordered = data.clone();
+        unordered[4] = 200;
+        assert!(decode(&unordered).is_err());
+    }
+
+    #[test]
+    fn verifies_batched_responses() {
+        let server = TestServer::new(0, u64::MAX);
+        let public_key = TestServer::root_key().verifying_key();
+        let now = "2024-03-10T12:00:00Z".parse().unwrap();
+        let nonces: Vec<[u8; NONCE_LENGTH]> = (0..5).map(|i| [i; NONCE_LENGTH]).collect();
+
+        let responses = server.respond(&nonces, now);
+        for (nonce, response) in nonces.iter().zip(&responses) {
+            let time = verify(response, nonce, &public_key).unwrap();
+            assert_eq!(time.midpoint, now);
+            assert_eq!(time.radius, Duration::seconds(1));
+        }
+
+        assert!(matches!(
+            verify(&responses[0], &nonces[1], &public_key),
+            Err(RoughtimeError::BadPath)
+        ));
+        let other_key = SigningKey::from_bytes(&[8; 32]).verifying_key();
+        assert!(matches!(
+            verify(&responses[0], &nonces[0], &other_key),
+            Err(RoughtimeError::BadSignature("delegation"))
+        ));
+        let mut tampered = decode(&responses[0]).unwrap();
+        let srep = tampered.get_mut(&SREP).unwrap();
+        let at = srep.len() - 1;
+        srep[at] ^= 1;
+        assert!(matches!(
+            verify(&encode(&tampered), &nonces[0], &public_key),
+            Err(RoughtimeError::BadSignature("response"))
+        ));
+
+        let expired = TestServer::new(0, 1_000);
+        assert!(matches!(
+            verify(
+                &expired.respond(&nonces[..1], now)[0],
+                &nonces[0],
+                &public_key
+            ),
+            Err(RoughtimeError::OutsideValidity)
+        ));
+    }
+
+    #[test]
+    fn public_keys() {
+        let key = TestServer::root_key().verifying_key();
+        let hex: String = key.to_bytes().iter().map(|b| format!("{b:02x}")).collect();
+        assert_eq!(parse_public_key(&hex).unwrap(), key);
+        assert_eq!(
+            parse_public_key(&STANDARD.encode(key.to_bytes())).unwrap(),
+            key
+        );
+        assert!(parse_public_key("c2hvcnQ=").is_err());
+        assert!(parse_public_key("not base64!").is_err());
+    }
+
+    #[test]
+    fn queries_local_server() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = socket.local_addr().unwrap().port();
+        thread::spawn(move || TestServer::new(0, u64::MAX).serve(socket));
+
+        let server = Server::parse(&format!("127.0.0.1:{port}"), Default::default()).unwrap();
+        let result = query(&server, &TestServer::root_key().verifying_key()).unwrap();
+        assert!(
+            result.offset().abs() < 1000.0,
+            "offset {}ms",
+            result.offset()
+        );
+        assert!(result.uncertainty() >= 1000.0);
+    }
+}
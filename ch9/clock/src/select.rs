@@ -0,0 +1,263 @@
+use std::{error::Error, fmt::Display};
+
+/// Fewest survivors the cluster algorithm prunes down to
+// see: https://datatracker.ietf.org/doc/html/rfc5905#section-11.2.2
+const CLUSTER_MIN: usize = 3;
+
+/// Offsets are measured in whole milliseconds, so no sample is trusted
+/// to be closer than that
+const MIN_DISTANCE: f64 = 1.0;
+
+/// One server's measurement, in milliseconds
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub name: String,
+    pub offset: f64,
+    /// Root distance, the true offset lies within `offset ± distance`
+    pub distance: f64,
+}
+
+impl Sample {
+    fn distance(&self) -> f64 {
+        self.distance.max(MIN_DISTANCE)
+    }
+
+    fn interval(&self) -> (f64, f64) {
+        (self.offset - self.distance(), self.offset + self.distance())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rejection {
+    /// No interval is shared by more than half of the samples
+    NoMajority,
+    /// The interval misses the one shared by the majority
+    Falseticker { low: f64, high: f64 },
+    /// Pruned by clustering as the farthest from the other survivors
+    Outlier { jitter: f64 },
+}
+
+impl Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Rejection::NoMajority => write!(f, "no majority of servers agrees"),
+            Rejection::Falseticker { low, high } => {
+                write!(f, "falseticker, outside [{low:.1}ms, {high:.1}ms]")
+            }
+            Rejection::Outlier { jitter } => {
+                write!(f, "outlier, {jitter:.1}ms selection jitter")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Selection {
+    pub survivors: Vec<Sample>,
+    pub rejected: Vec<(Sample, Rejection)>,
+}
+
+impl Selection {
+    /// Mean offset of the survivors weighted by the inverse of their distance
+    pub fn offset(&self) -> Option<f64> {
+        if self.survivors.is_empty() {
+            return None;
+        }
+        let (sum, weights) = self.survivors.iter().fold((0.0, 0.0), |(s, w), sample| {
+            (
+                s + sample.offset / sample.distance(),
+                w + 1.0 / sample.distance(),
+            )
+        });
+        Some(sum / weights)
+    }
+}
+
+#[derive(Debug)]
+pub struct TooFewSurvivors {
+    pub survivors: usize,
+    pub required: usize,
+}
+
+impl Display for TooFewSurvivors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} servers survived selection, {} required",
+            self.survivors, self.required
+        )
+    }
+}
+
+impl Error for TooFewSurvivors {}
+
+/// Smallest interval shared by a majority of the samples, allowing as few
+/// falsetickers as possible
+// see: https://en.wikipedia.org/wiki/Marzullo%27s_algorithm
+fn intersection(samples: &[Sample]) -> Option<(f64, f64)> {
+    // +1 opens an interval, -1 closes one
+    let mut edges: Vec<(f64, i32)> = samples
+        .iter()
+        .flat_map(|sample| {
+            let (low, high) = sample.interval();
+            [(low, 1), (high, -1)]
+        })
+        .collect();
+    // at equal values intervals open before others close, so touching ones overlap
+    edges.sort_by(|a, b| a.0.total_cmp(&b.0).then(b.1.cmp(&a.1)));
+
+    fn first_overlap<'a>(
+        edges: impl Iterator<Item = &'a (f64, i32)>,
+        sign: i32,
+        required: i32,
+    ) -> Option<f64> {
+        let mut count = 0;
+        for &(value, edge) in edges {
+            count += sign * edge;
+            if count >= required {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    let n = samples.len();
+    for falsetickers in 0..n.div_ceil(2) {
+        let required = (n - falsetickers) as i32;
+        let low = first_overlap(edges.iter(), 1, required);
+        let high = first_overlap(edges.iter().rev(), -1, required);
+        if let (Some(low), Some(high)) = (low, high) {
+            if low <= high {
+                return Some((low, high));
+            }
+        }
+    }
+    None
+}
+
+/// Root mean square of the offset differences between `samples[i]` and the rest
+fn selection_jitter(samples: &[Sample], i: usize) -> f64 {
+    let squares: f64 = samples
+        .iter()
+        .map(|other| (samples[i].offset - other.offset).powi(2))
+        .sum();
+    (squares / (samples.len() - 1) as f64).sqrt()
+}
+
+/// Keep the samples that agree with the majority, then prune the ones
+/// farthest from the rest until the spread is within what the closest
+/// server can resolve.
+///
+/// Each server answers once, so its distance stands in for the peer jitter
+/// of the RFC 5905 cluster algorithm.
+// see: https://datatracker.ietf.org/doc/html/rfc5905#section-11.2.1
+pub fn select(samples: Vec<Sample>) -> Selection {
+    let mut selection = Selection::default();
+
+    let Some((low, high)) = intersection(&samples) else {
+        selection.rejected = samples
+            .into_iter()
+            .map(|sample| (sample, Rejection::NoMajority))
+            .collect();
+        return selection;
+    };
+
+    for sample in samples {
+        let (start, end) = sample.interval();
+        if start <= high && end >= low {
+            selection.survivors.push(sample);
+        } else {
+            let rejection = Rejection::Falseticker { low, high };
+            selection.rejected.push((sample, rejection));
+        }
+    }
+
+    while selection.survivors.len() > CLUSTER_MIN {
+        let survivors = &selection.survivors;
+        let (worst, jitter) = (0..survivors.len())
+            .map(|i| (i, selection_jitter(survivors, i)))
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap();
+        let resolution = survivors
+            .iter()
+            .map(Sample::distance)
+            .fold(f64::INFINITY, f64::min);
+        if jitter <= resolution {
+            break;
+        }
+        let sample = selection.survivors.remove(worst);
+        selection
+            .rejected
+            .push((sample, Rejection::Outlier { jitter }));
+    }
+
+    selection
+}
+
+#[cfg(test)]
+mod select_test {
+    use super::*;
+
+    fn sample(name: &str, offset: f64, distance: f64) -> Sample {
+        Sample {
+            name: name.to_string(),
+            offset,
+            distance,
+        }
+    }
+
+    fn names(samples: &[Sample]) -> Vec<&str> {
+        samples.iter().map(|s| s.name.as_str()).collect()
+    }
+
+    #[test]
+    fn rejects_falsetickers() {
+        let selection = select(vec![
+            sample("a", 10.0, 5.0),
+            sample("b", 12.0, 5.0),
+            sample("c", 500.0, 5.0),
+        ]);
+        assert_eq!(names(&selection.survivors), ["a", "b"]);
+        assert_eq!(selection.rejected[0].0.name, "c");
+        assert_eq!(
+            selection.rejected[0].1,
+            Rejection::Falseticker {
+                low: 7.0,
+                high: 15.0
+            }
+        );
+        assert_eq!(selection.offset(), Some(11.0));
+    }
+
+    #[test]
+    fn needs_a_majority() {
+        let selection = select(vec![sample("a", 0.0, 1.0), sample("b", 100.0, 1.0)]);
+        assert!(selection.survivors.is_empty());
+        assert_eq!(selection.rejected.len(), 2);
+        assert_eq!(selection.rejected[0].1, Rejection::NoMajority);
+        assert_eq!(selection.offset(), None);
+
+        assert_eq!(select(Vec::new()).offset(), None);
+    }
+
+    #[test]
+    fn prunes_outliers() {
+        // all intervals overlap, but "e" is far from the others
+        let selection = select(vec![
+            sample("a", 0.0, 40.0),
+            sample("b", 1.0, 40.0),
+            sample("c", 2.0, 40.0),
+            sample("d", 1.0, 2.0),
+            sample("e", 60.0, 40.0),
+        ]);
+        assert_eq!(names(&selection.survivors), ["a", "b", "c", "d"]);
+        assert_eq!(selection.rejected[0].0.name, "e");
+        assert!(matches!(selection.rejected[0].1, Rejection::Outlier { .. }));
+    }
+
+    #[test]
+    fn zero_distance_is_finite() {
+        let selection = select(vec![sample("a", 3.0, 0.0), sample("b", 3.0, 0.0)]);
+        assert_eq!(selection.offset(), Some(3.0));
+    }
+}